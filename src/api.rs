@@ -1,4 +1,7 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -6,16 +9,40 @@ use serde::{Deserialize, Serialize};
 #[serde(transparent)]
 pub(crate) struct NetworkId<'a>(&'a str);
 
+impl<'a> NetworkId<'a> {
+    pub(crate) fn new(id: &'a str) -> Self {
+        Self(id)
+    }
+}
+
 impl AsRef<Path> for NetworkId<'_> {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
+impl std::fmt::Display for NetworkId<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(transparent)]
 pub(crate) struct EndpointId<'a>(&'a str);
 
+impl<'a> EndpointId<'a> {
+    pub(crate) fn new(id: &'a str) -> Self {
+        Self(id)
+    }
+}
+
+impl AsRef<Path> for EndpointId<'_> {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
 impl std::fmt::Display for EndpointId<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.fmt(f)
@@ -26,6 +53,12 @@ impl std::fmt::Display for EndpointId<'_> {
 #[serde(transparent)]
 pub(crate) struct SandboxKey<'a>(&'a str);
 
+impl AsRef<Path> for SandboxKey<'_> {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all(deserialize = "PascalCase"))]
 pub(crate) struct CreateNetworkRequest<'a> {
@@ -52,7 +85,18 @@ pub(crate) struct CreateNetworkOptions<'a> {
 pub(crate) struct CreateNetworkGenericOptions<'a> {
     #[serde(rename = "wireguard-config")]
     pub(crate) config: Option<&'a str>,
-    // Other options are ignored
+    #[serde(rename = "wireguard-config-lazy")]
+    pub(crate) config_lazy: Option<&'a str>,
+    #[serde(rename = "wireguard-dns")]
+    pub(crate) dns: Option<&'a str>,
+    #[serde(rename = "wireguard-killswitch")]
+    pub(crate) killswitch: Option<&'a str>,
+    #[serde(rename = "wireguard-config-inline")]
+    pub(crate) config_inline: Option<&'a str>,
+    /// Everything else, including `wireguard.*` options that make up an
+    /// inline configuration. Other options are ignored, whatever their type.
+    #[serde(flatten)]
+    pub(crate) other: BTreeMap<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -110,6 +154,15 @@ pub(crate) struct DeleteEndpointRequest<'a> {
     pub(crate) endpoint_id: EndpointId<'a>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+pub(crate) struct EndpointOperInfoRequest<'a> {
+    #[serde(borrow, rename = "NetworkID")]
+    pub(crate) network_id: NetworkId<'a>,
+    #[serde(borrow, rename = "EndpointID")]
+    pub(crate) endpoint_id: EndpointId<'a>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all(deserialize = "PascalCase"))]
 pub(crate) struct LeaveRequest<'a> {
@@ -144,6 +197,17 @@ pub(crate) struct JoinOptions {
     // Options are ignored altogether for now
 }
 
+/// Request for the `[Peer]` sections of all the endpoints of a network, or
+/// of a single endpoint.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+pub(crate) struct ExportPeerRequest<'a> {
+    #[serde(borrow, default, rename = "NetworkID")]
+    pub(crate) network_id: Option<NetworkId<'a>>,
+    #[serde(borrow, default, rename = "EndpointID")]
+    pub(crate) endpoint_id: Option<EndpointId<'a>>,
+}
+
 #[derive(Serialize, Debug)]
 pub(crate) struct ErrorResponse<'a> {
     pub(crate) err: &'a str,
@@ -182,4 +246,29 @@ mod tests {
         assert_eq!(req.options.enable_ipv6, Some(false));
         assert_eq!(req.options.generic.config, Some("foo-bar"));
     }
+
+    #[test]
+    fn test_create_network_inline_options() {
+        let value = json!({
+            "NetworkID":"ec22489c52c934f9f788cc99483deb35070eae17b7712e12e569f8a39e0b9a4b",
+            "Options":{
+                "com.docker.network.generic":{
+                    "wireguard-dns":"override",
+                    "wireguard.private-key":"yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=",
+                    "wireguard.peer.0.endpoint":"192.0.2.1:51820",
+                    "com.example.retries":3,
+                    "com.example.enabled":true}},
+        });
+        let s = value.to_string();
+        let req: CreateNetworkRequest = serde_json::from_str(&s).unwrap();
+        let generic = req.options.generic;
+        assert_eq!(generic.config, None);
+        assert_eq!(generic.dns, Some("override"));
+        assert_eq!(generic.other.len(), 4);
+        assert_eq!(
+            generic.other["wireguard.peer.0.endpoint"].as_str(),
+            Some("192.0.2.1:51820")
+        );
+        assert_eq!(generic.other["com.example.retries"], json!(3));
+    }
 }
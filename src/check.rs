@@ -0,0 +1,71 @@
+//! The `check` subcommand, that finds mistakes in configuration files
+//! without creating any interface.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use crate::wg::{self, Severity};
+
+/// Check each file, or each `.conf` file of each directory, and print the
+/// diagnostics and a summary to stdout. Fails if there are errors, or if a
+/// file can't be read.
+pub(crate) fn run(paths: &[PathBuf]) -> ExitCode {
+    let mut files = 0;
+    let mut errors = 0;
+    let mut warnings = 0;
+    for path in paths {
+        let config_paths = match config_paths(path) {
+            Ok(config_paths) => config_paths,
+            Err(err) => {
+                println!("{}: error: {err}", path.display());
+                errors += 1;
+                continue;
+            }
+        };
+        for path in config_paths {
+            files += 1;
+            let text = match std::fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(err) => {
+                    println!("{}: error: {err}", path.display());
+                    errors += 1;
+                    continue;
+                }
+            };
+            for diagnostic in wg::check_config(&text) {
+                match diagnostic.severity {
+                    Severity::Error => errors += 1,
+                    Severity::Warning => warnings += 1,
+                }
+                match diagnostic.span {
+                    Some(span) => print!("{}:{}:{}: ", path.display(), span.line, span.start),
+                    None => print!("{}: ", path.display()),
+                }
+                println!("{}: {}", diagnostic.severity, diagnostic.message);
+            }
+        }
+    }
+    println!("Checked {files} file(s): {errors} error(s), {warnings} warning(s)");
+    if errors > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// The configuration files in `path`, sorted, or `path` itself if it's not
+/// a directory.
+fn config_paths(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "conf") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
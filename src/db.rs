@@ -1,21 +1,205 @@
+use std::fs::File;
+use std::io::Write as _;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-use serde::{Deserialize, Serialize};
+use rustix::fs::FlockOperation;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::api::NetworkId;
+use crate::api::{EndpointId, NetworkId};
+use crate::wg::{ConfigSource, Key};
 
 pub(crate) struct Db {
     path: PathBuf,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct Network {
+    /// Name of the configuration file.
+    #[serde(default)]
     config: String,
+    /// Configuration text, for networks created with an inline
+    /// configuration instead of a file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inline_config: Option<String>,
+    #[serde(default)]
+    options: NetworkOptions,
 }
 
 impl Network {
-    pub(crate) fn config(&self) -> &str {
-        &self.config
+    pub(crate) fn new(config: String, options: NetworkOptions) -> Self {
+        Self {
+            config,
+            inline_config: None,
+            options,
+        }
+    }
+
+    pub(crate) fn new_inline(text: String, options: NetworkOptions) -> Self {
+        Self {
+            config: String::new(),
+            inline_config: Some(text),
+            options,
+        }
+    }
+
+    pub(crate) fn config_source(&self) -> ConfigSource<'_> {
+        match &self.inline_config {
+            Some(text) => ConfigSource::Inline(text),
+            None => ConfigSource::File(&self.config),
+        }
+    }
+
+    pub(crate) fn options(&self) -> &NetworkOptions {
+        &self.options
+    }
+}
+
+/// Inline configurations are redacted, since they contain the private key.
+impl std::fmt::Debug for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Network")
+            .field("config", &self.config)
+            .field(
+                "inline_config",
+                &self.inline_config.as_ref().map(|_| "<redacted>"),
+            )
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// Driver options given when the network was created.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct NetworkOptions {
+    #[serde(default)]
+    pub(crate) dns: DnsMode,
+    #[serde(default)]
+    pub(crate) killswitch: bool,
+}
+
+/// How the container resolv.conf is handled (`wireguard-dns` option).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DnsMode {
+    /// Leave resolv.conf to Docker.
+    #[default]
+    Docker,
+    /// Replace resolv.conf with the `DNS` servers from the configuration.
+    Override,
+}
+
+impl std::str::FromStr for DnsMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(Self::Docker),
+            "override" => Ok(Self::Override),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An endpoint of a network. It owns an interface while it is joined to a
+/// sandbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Endpoint {
+    #[serde(default)]
+    network_id: String,
+    /// Addresses reported to Docker, or added by the plugin.
+    #[serde(default)]
+    addresses: Vec<String>,
+    /// Creation time, in RFC 3339 format.
+    #[serde(default)]
+    created: String,
+    #[serde(default)]
+    interface: Option<String>,
+    #[serde(default)]
+    sandbox_key: Option<PathBuf>,
+    /// Index of the interface in the sandbox, once Docker has moved it there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sandbox_index: Option<u32>,
+    /// Digest of the configuration applied to the interface.
+    #[serde(default)]
+    config_hash: Option<String>,
+    /// Generated private key, for networks with an address pool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private_key: Option<Key>,
+}
+
+impl Endpoint {
+    pub(crate) fn new(network_id: NetworkId, addresses: Vec<String>) -> Self {
+        Self {
+            network_id: network_id.to_string(),
+            addresses,
+            created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            interface: None,
+            sandbox_key: None,
+            sandbox_index: None,
+            config_hash: None,
+            private_key: None,
+        }
+    }
+
+    /// An endpoint with its own private key, and an address from the pool
+    /// of the network.
+    pub(crate) fn new_generated(network_id: NetworkId, address: String, private_key: Key) -> Self {
+        Self {
+            private_key: Some(private_key),
+            ..Self::new(network_id, vec![address])
+        }
+    }
+
+    pub(crate) fn network_id(&self) -> &str {
+        &self.network_id
+    }
+
+    pub(crate) fn addresses(&self) -> &[String] {
+        &self.addresses
+    }
+
+    pub(crate) fn private_key(&self) -> Option<&Key> {
+        self.private_key.as_ref()
+    }
+
+    pub(crate) fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    pub(crate) fn sandbox_key(&self) -> Option<&Path> {
+        self.sandbox_key.as_deref()
+    }
+
+    pub(crate) fn sandbox_index(&self) -> Option<u32> {
+        self.sandbox_index
+    }
+
+    pub(crate) fn config_hash(&self) -> Option<&str> {
+        self.config_hash.as_deref()
+    }
+
+    /// Record the interface created when joining a sandbox.
+    pub(crate) fn join(&mut self, interface: String, sandbox_key: PathBuf, config_hash: String) {
+        self.interface = Some(interface);
+        self.sandbox_key = Some(sandbox_key);
+        self.config_hash = Some(config_hash);
+    }
+
+    /// Record the index of the interface once it shows up in the sandbox.
+    pub(crate) fn set_sandbox_index(&mut self, index: u32) {
+        self.sandbox_index = Some(index);
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.interface = None;
+        self.sandbox_key = None;
+        self.sandbox_index = None;
+        self.config_hash = None;
+    }
+
+    pub(crate) fn set_config_hash(&mut self, config_hash: String) {
+        self.config_hash = Some(config_hash);
     }
 }
 
@@ -28,37 +212,301 @@ impl Db {
         self.path.join(network_id).with_extension("json")
     }
 
+    fn endpoints_path(&self) -> PathBuf {
+        self.path.join("endpoints")
+    }
+
+    fn endpoint_path(&self, endpoint_id: EndpointId) -> PathBuf {
+        self.endpoints_path()
+            .join(endpoint_id)
+            .with_extension("json")
+    }
+
+    fn pending_deletions_path(&self) -> PathBuf {
+        self.path.join("pending_deletions.json")
+    }
+
+    /// Take an advisory lock on the database directory. The lock is released
+    /// when the returned file is dropped.
+    ///
+    /// The directory is opened again every time, so that the lock also
+    /// excludes other threads of this process.
+    fn lock(&self, operation: FlockOperation) -> Result<File, std::io::Error> {
+        let dir = File::open(&self.path)?;
+        rustix::fs::flock(&dir, operation)?;
+        Ok(dir)
+    }
+
+    fn lock_shared(&self) -> Result<File, std::io::Error> {
+        self.lock(FlockOperation::LockShared)
+    }
+
+    fn lock_exclusive(&self) -> Result<File, std::io::Error> {
+        self.lock(FlockOperation::LockExclusive)
+    }
+
     pub(crate) fn create_network(
         &self,
         network_id: NetworkId,
-        config: String,
+        network: &Network,
     ) -> Result<(), std::io::Error> {
-        let network = Network { config };
-        let network = serde_json::to_string(&network)?;
-        let path = self.network_path(network_id);
-        // TODO: locking
-        std::fs::write(path, network)
+        let _lock = self.lock_exclusive()?;
+        write_json(&self.network_path(network_id), network)
     }
 
     pub(crate) fn delete_network(&self, network_id: NetworkId) -> Result<(), std::io::Error> {
-        let path = self.network_path(network_id);
-        // TODO: locking
-        std::fs::remove_file(path)
+        let _lock = self.lock_exclusive()?;
+        std::fs::remove_file(self.network_path(network_id))
     }
 
     pub(crate) fn get_network(&self, network_id: NetworkId) -> Result<Network, std::io::Error> {
-        let path = self.network_path(network_id);
-        let network = match std::fs::read_to_string(path) {
-            Ok(network) => network,
+        let _lock = self.lock_shared()?;
+        read_json(&self.network_path(network_id))
+    }
+
+    pub(crate) fn put_endpoint(
+        &self,
+        endpoint_id: EndpointId,
+        endpoint: &Endpoint,
+    ) -> Result<(), std::io::Error> {
+        let _lock = self.lock_exclusive()?;
+        write_json(&self.endpoint_path(endpoint_id), endpoint)
+    }
+
+    /// Add an endpoint built from the existing ones, such as one with an
+    /// address allocated from a pool. Both happen under the same lock, so
+    /// that concurrent requests can't pick the same address.
+    pub(crate) fn put_endpoint_with<T, E: From<std::io::Error>>(
+        &self,
+        endpoint_id: EndpointId,
+        f: impl FnOnce(&[(String, Endpoint)]) -> Result<(Endpoint, T), E>,
+    ) -> Result<T, E> {
+        let _lock = self.lock_exclusive()?;
+        let (endpoint, value) = f(&self.read_endpoints()?)?;
+        write_json(&self.endpoint_path(endpoint_id), &endpoint)?;
+        Ok(value)
+    }
+
+    pub(crate) fn get_endpoint(&self, endpoint_id: EndpointId) -> Result<Endpoint, std::io::Error> {
+        let _lock = self.lock_shared()?;
+        read_json(&self.endpoint_path(endpoint_id))
+    }
+
+    /// Update an endpoint in place. It's not an error if it does not exist.
+    pub(crate) fn update_endpoint(
+        &self,
+        endpoint_id: EndpointId,
+        f: impl FnOnce(&mut Endpoint),
+    ) -> Result<(), std::io::Error> {
+        let _lock = self.lock_exclusive()?;
+        let path = self.endpoint_path(endpoint_id);
+        let mut endpoint = match read_json(&path) {
+            Ok(endpoint) => endpoint,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
             Err(err) => return Err(err),
         };
-        let network = serde_json::from_str(&network)?;
-        Ok(network)
+        f(&mut endpoint);
+        write_json(&path, &endpoint)
+    }
+
+    /// Remove an endpoint. It's not an error if it does not exist.
+    pub(crate) fn delete_endpoint(&self, endpoint_id: EndpointId) -> Result<(), std::io::Error> {
+        let _lock = self.lock_exclusive()?;
+        match std::fs::remove_file(self.endpoint_path(endpoint_id)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn list_endpoints(&self) -> Result<Vec<(String, Endpoint)>, std::io::Error> {
+        let _lock = self.lock_shared()?;
+        self.read_endpoints()
+    }
+
+    /// Callers must hold a lock. Records that can't be parsed are skipped,
+    /// so that one of them doesn't hide all the other endpoints.
+    fn read_endpoints(&self) -> Result<Vec<(String, Endpoint)>, std::io::Error> {
+        let mut endpoints = Vec::new();
+        for entry in std::fs::read_dir(self.endpoints_path())? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(endpoint_id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            match read_json(&path) {
+                Ok(endpoint) => endpoints.push((endpoint_id.to_owned(), endpoint)),
+                Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                    log::error!(err:display; "Skipping endpoint record");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(endpoints)
+    }
+
+    /// Names of interfaces that should be deleted as soon as they show up
+    /// in the plugin namespace.
+    pub(crate) fn pending_deletions(&self) -> Result<Vec<String>, std::io::Error> {
+        let _lock = self.lock_shared()?;
+        match read_json(&self.pending_deletions_path()) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            result => result,
+        }
+    }
+
+    pub(crate) fn set_pending_deletions(&self, names: &[String]) -> Result<(), std::io::Error> {
+        let _lock = self.lock_exclusive()?;
+        write_json(&self.pending_deletions_path(), &names)
+    }
+}
+
+/// Read a record, with an error that names the file if it can't be parsed.
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, std::io::Error> {
+    let contents = std::fs::read(path)?;
+    serde_json::from_slice(&contents).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("corrupt record {}: {err}", path.display()),
+        )
+    })
+}
+
+/// Replace a record atomically: the new contents are written to a temporary
+/// file, which is then renamed over the old one, so that a crash leaves
+/// either the old or the new version. Callers must hold the exclusive lock.
+///
+/// Records are only readable by the owner, since endpoints can hold private
+/// keys.
+fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<(), std::io::Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let contents = serde_json::to_vec(value)?;
+    let tmp_path = path.with_extension("tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp_path, path)?;
+    // make the rename itself durable
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
     }
+    Ok(())
 }
 
 pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Db, std::io::Error> {
     let path = path.as_ref();
-    std::fs::create_dir_all(path)?;
-    Ok(Db::new(path.to_owned()))
+    let db = Db::new(path.to_owned());
+    std::fs::create_dir_all(db.endpoints_path())?;
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty database in a directory of its own.
+    fn test_db(name: &str) -> (Db, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("wg-db-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        (open(&dir).unwrap(), dir)
+    }
+
+    #[test]
+    fn test_endpoints() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (db, dir) = test_db("endpoints");
+        let network_id = NetworkId::new("n1");
+        let endpoint_id = EndpointId::new("e1");
+        let endpoint = Endpoint::new(network_id, vec!["10.0.0.2/32".to_owned()]);
+        db.put_endpoint(endpoint_id, &endpoint).unwrap();
+
+        db.update_endpoint(endpoint_id, |endpoint| {
+            endpoint.join(
+                "wgdkr-e1".to_owned(),
+                "/run/netns/1".into(),
+                "hash".to_owned(),
+            )
+        })
+        .unwrap();
+        let endpoint = db.get_endpoint(endpoint_id).unwrap();
+        assert_eq!(endpoint.network_id(), "n1");
+        assert_eq!(endpoint.interface(), Some("wgdkr-e1"));
+        assert_eq!(endpoint.config_hash(), Some("hash"));
+
+        // records are replaced through a temporary file, which is renamed
+        let path = db.endpoint_path(endpoint_id);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!path.with_extension("tmp").exists());
+
+        // updating or deleting an endpoint that is gone is not an error
+        db.delete_endpoint(endpoint_id).unwrap();
+        db.delete_endpoint(endpoint_id).unwrap();
+        db.update_endpoint(endpoint_id, Endpoint::leave).unwrap();
+        let err = db.get_endpoint(endpoint_id).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_debug_redacted() {
+        let key = Key::generate();
+        let endpoint =
+            Endpoint::new_generated(NetworkId::new("n1"), "10.0.0.2/24".to_owned(), key.clone());
+        assert!(!format!("{endpoint:?}").contains(&key.to_string()));
+
+        let text = format!("[Interface]\nPrivateKey = {key}\n");
+        let network = Network::new_inline(text, NetworkOptions::default());
+        assert!(!format!("{network:?}").contains(&key.to_string()));
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let (db, dir) = test_db("corrupt");
+        let endpoint = Endpoint::new(NetworkId::new("n1"), Vec::new());
+        db.put_endpoint(EndpointId::new("good"), &endpoint).unwrap();
+        let bad_path = db.endpoint_path(EndpointId::new("bad"));
+        std::fs::write(&bad_path, "{\"network_id\": ").unwrap();
+        // left behind by a write that didn't complete
+        std::fs::write(db.endpoints_path().join("good.tmp"), "{").unwrap();
+
+        let err = db.get_endpoint(EndpointId::new("bad")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let expected = format!("corrupt record {}: ", bad_path.display());
+        assert!(err.to_string().starts_with(&expected), "{err}");
+
+        let endpoints = db.list_endpoints().unwrap();
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints[0].0, "good");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock() {
+        let (db, dir) = test_db("lock");
+        let try_lock = |operation| rustix::fs::flock(File::open(&dir).unwrap(), operation);
+
+        let lock = db.lock_exclusive().unwrap();
+        assert!(try_lock(FlockOperation::NonBlockingLockShared).is_err());
+        drop(lock);
+
+        let lock = db.lock_shared().unwrap();
+        assert!(try_lock(FlockOperation::NonBlockingLockShared).is_ok());
+        assert!(try_lock(FlockOperation::NonBlockingLockExclusive).is_err());
+        drop(lock);
+        assert!(try_lock(FlockOperation::NonBlockingLockExclusive).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -1,7 +1,37 @@
 use std::io::Write;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// How log lines are written.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    /// Timestamp, level and message, followed by `key=value` pairs.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LogOutput {
+    Stderr,
+    Stdout,
+    /// Append to a file.
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LogSettings {
+    pub(crate) level: log::LevelFilter,
+    pub(crate) format: LogFormat,
+    pub(crate) output: LogOutput,
+}
 
 struct Logger<Writer> {
     max_level: log::LevelFilter,
+    format: LogFormat,
     output: Writer,
 }
 
@@ -9,6 +39,7 @@ impl Logger<()> {
     const fn new() -> Self {
         Self {
             max_level: log::LevelFilter::Off,
+            format: LogFormat::Text,
             output: (),
         }
     }
@@ -21,23 +52,15 @@ impl<T> Logger<T> {
         self
     }
 
-    fn increase_level(mut self) -> Self {
-        let new_level = match self.max_level {
-            log::LevelFilter::Off => log::LevelFilter::Error,
-            log::LevelFilter::Error => log::LevelFilter::Warn,
-            log::LevelFilter::Warn => log::LevelFilter::Info,
-            log::LevelFilter::Info => log::LevelFilter::Debug,
-            log::LevelFilter::Debug => log::LevelFilter::Trace,
-            _ => log::LevelFilter::Trace,
-        };
-        self.max_level = new_level;
-        log::set_max_level(new_level);
+    fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
         self
     }
 
     fn output<W>(self, writer: W) -> Logger<W> {
         Logger {
             max_level: self.max_level,
+            format: self.format,
             output: writer,
         }
     }
@@ -62,7 +85,9 @@ where
     for<'a> &'a T: Write,
 {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= self.max_level && metadata.target().starts_with(module_path!())
+        // only our own logs, not the ones of dependencies
+        metadata.level() <= self.max_level
+            && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
     }
 
     fn log(&self, record: &log::Record) {
@@ -70,28 +95,60 @@ where
             return;
         }
         let timestamp = humantime::format_rfc3339_millis(std::time::SystemTime::now());
-        struct Printer<T>(T);
         use log::kv;
-        impl<'kvs, T: Write> kv::VisitSource<'kvs> for Printer<T> {
-            fn visit_pair(
-                &mut self,
-                key: kv::Key<'kvs>,
-                value: kv::Value<'kvs>,
-            ) -> Result<(), kv::Error> {
-                write!(self.0, " {key}={value}").expect(FAILED_WRITE_MSG);
-                Ok(())
+        match self.format {
+            LogFormat::Text => {
+                struct Printer<T>(T);
+                impl<'kvs, T: Write> kv::VisitSource<'kvs> for Printer<T> {
+                    fn visit_pair(
+                        &mut self,
+                        key: kv::Key<'kvs>,
+                        value: kv::Value<'kvs>,
+                    ) -> Result<(), kv::Error> {
+                        write!(self.0, " {key}={value}").expect(FAILED_WRITE_MSG);
+                        Ok(())
+                    }
+                }
+                write!(
+                    &self.output,
+                    "{timestamp} {level} {args}",
+                    timestamp = timestamp,
+                    level = record.level(),
+                    args = record.args(),
+                )
+                .expect(FAILED_WRITE_MSG);
+                let _ = record.key_values().visit(&mut Printer(&self.output));
+                (&self.output).write_all(b"\n").expect(FAILED_WRITE_MSG);
+            }
+            LogFormat::Json => {
+                struct Collector(serde_json::Map<String, serde_json::Value>);
+                impl<'kvs> kv::VisitSource<'kvs> for Collector {
+                    fn visit_pair(
+                        &mut self,
+                        key: kv::Key<'kvs>,
+                        value: kv::Value<'kvs>,
+                    ) -> Result<(), kv::Error> {
+                        self.0.insert(key.to_string(), value.to_string().into());
+                        Ok(())
+                    }
+                }
+                let mut fields = Collector(serde_json::Map::new());
+                fields
+                    .0
+                    .insert("timestamp".into(), timestamp.to_string().into());
+                fields
+                    .0
+                    .insert("level".into(), record.level().as_str().into());
+                fields
+                    .0
+                    .insert("message".into(), record.args().to_string().into());
+                let _ = record.key_values().visit(&mut fields);
+                let line = serde_json::Value::Object(fields.0).to_string() + "\n";
+                (&self.output)
+                    .write_all(line.as_bytes())
+                    .expect(FAILED_WRITE_MSG);
             }
         }
-        write!(
-            &self.output,
-            "{timestamp} {level} {args}",
-            timestamp = timestamp,
-            level = record.level(),
-            args = record.args(),
-        )
-        .expect(FAILED_WRITE_MSG);
-        let _ = record.key_values().visit(&mut Printer(&self.output));
-        (&self.output).write_all(b"\n").expect(FAILED_WRITE_MSG);
     }
 
     fn flush(&self) {
@@ -99,42 +156,25 @@ where
     }
 }
 
-pub(crate) fn configure_logging() -> Result<(), ()> {
-    let verbose = std::env::var("DEBUG")
-        .map(|v| v.trim() == "1")
-        .unwrap_or(false);
-
-    let base_verbosity = if cfg!(debug_assertions) {
-        log::Level::Debug
-    } else {
-        log::Level::Warn
-    };
-    let mut logger = Logger::new().level(base_verbosity.to_level_filter());
-    if verbose {
-        logger = logger.increase_level();
-    }
+pub(crate) fn configure_logging(settings: &LogSettings) -> Result<(), ()> {
+    let logger = Logger::new().level(settings.level).format(settings.format);
 
-    match std::env::var_os("LOGFILE") {
-        Some(path) => {
-            if path == "stderr" {
-                logger.output(std::io::stderr()).init().map_err(|_| ())
-            } else if path == "stdout" {
-                logger.output(std::io::stdout()).init().map_err(|_| ())
-            } else {
-                let file = match std::fs::OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(path)
-                {
-                    Ok(file) => file,
-                    Err(e) => {
-                        eprintln!("Failed to open log file: {}", e);
-                        return Err(());
-                    }
-                };
-                logger.output(file).init().map_err(|_| ())
-            }
+    match &settings.output {
+        LogOutput::Stderr => logger.output(std::io::stderr()).init().map_err(|_| ()),
+        LogOutput::Stdout => logger.output(std::io::stdout()).init().map_err(|_| ()),
+        LogOutput::File(path) => {
+            let file = match std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+            {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("Failed to open log file: {}", e);
+                    return Err(());
+                }
+            };
+            logger.output(file).init().map_err(|_| ())
         }
-        None => logger.output(std::io::stderr()).init().map_err(|_| ()),
     }
 }
@@ -1,44 +1,130 @@
+use std::path::Path;
 use std::process::ExitCode;
 use std::sync::Arc;
 
 use api::ErrorResponse;
 use bytes::Bytes;
+use clap::Parser;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
 use log::log_enabled;
 use serde_json::json;
 use tokio::net::UnixListener;
 use wg::WgError;
 
 mod api;
+mod check;
 mod db;
 mod logging;
+mod metrics;
 mod netns;
+mod nft;
+mod sandbox;
+mod settings;
+mod systemd;
 mod wg;
 
 struct NetworkPluginService {
     db: Arc<db::Db>,
-    wg: wg::Wg,
+    wg: Arc<wg::Wg>,
     config_provider: wg::ConfigProvider,
+    killswitches: Arc<sandbox::KillSwitches>,
+    metrics: metrics::Metrics,
+    /// Sandbox setups still running after their Join returned.
+    sandbox_setups: std::sync::Mutex<tokio::task::JoinSet<()>>,
 }
 
 impl NetworkPluginService {
     fn new(
         db_path: impl AsRef<std::path::Path>,
         config_provider: wg::ConfigProvider,
+        interface_prefix: String,
     ) -> Result<Self, std::io::Error> {
         let db = Arc::new(db::open(db_path)?);
-        let wg = wg::Wg::new().expect("Failed to create WireGuard client");
+        let wg =
+            wg::Wg::new(db.clone(), interface_prefix).expect("Failed to create WireGuard client");
+        let wg = Arc::new(wg);
         Ok(Self {
             db,
             wg,
             config_provider,
+            killswitches: Default::default(),
+            metrics: Default::default(),
+            sandbox_setups: Default::default(),
         })
     }
 
+    /// Take back the interfaces of the endpoints that were joined to a
+    /// sandbox before a restart, with the current version of their
+    /// configuration.
+    async fn restore_interfaces(&self) -> Result<(), std::io::Error> {
+        let endpoints = tokio::task::block_in_place(|| self.db.list_endpoints())?;
+        for (endpoint_id, endpoint) in endpoints {
+            if endpoint.interface().is_none() {
+                continue;
+            }
+            if let Err(err) = self.restore_interface(&endpoint_id, &endpoint).await {
+                log::warn!(
+                    err:display,
+                    endpoint_id = endpoint_id.as_str();
+                    "Failed to restore interface"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn restore_interface(
+        &self,
+        endpoint_id: &str,
+        endpoint: &db::Endpoint,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let network_id = api::NetworkId::new(endpoint.network_id());
+        let network = tokio::task::block_in_place(|| self.db.get_network(network_id))?;
+        let source = network.config_source();
+        let mut config = self.config_provider.load(source).await?;
+        set_endpoint_identity(&mut config, endpoint)
+            .map_err(|_| "the endpoint has no generated key")?;
+        self.wg
+            .restore_interface(endpoint_id, endpoint, source.file_name(), config)
+            .await?;
+        Ok(())
+    }
+
+    /// Take over the kill switches of the endpoints that were joined when the
+    /// plugin last stopped, so that they are removed on Leave.
+    fn restore_killswitches(&self) -> Result<(), std::io::Error> {
+        for (endpoint_id, endpoint) in self.db.list_endpoints()? {
+            let Some(sandbox_key) = endpoint.sandbox_key() else {
+                continue;
+            };
+            let network_id = api::NetworkId::new(endpoint.network_id());
+            let killswitch = match self.db.get_network(network_id) {
+                Ok(network) => network.options().killswitch,
+                Err(err) => {
+                    log::warn!(
+                        err:display,
+                        endpoint_id = endpoint_id.as_str();
+                        "Failed to read network"
+                    );
+                    continue;
+                }
+            };
+            if killswitch {
+                self.killswitches.restore(
+                    endpoint_id,
+                    sandbox_key.to_owned(),
+                    endpoint.sandbox_index(),
+                );
+            }
+        }
+        Ok(())
+    }
+
     async fn serve(
         self: Arc<Self>,
         req: Request<hyper::body::Incoming>,
@@ -48,7 +134,10 @@ impl NetworkPluginService {
             path = req.uri().path();
             "Received request"
         );
-        ok_or_error_response(match (req.method(), req.uri().path()) {
+        if let Some(route) = req.uri().path().strip_prefix('/') {
+            self.metrics.count_request(route);
+        }
+        let result = match (req.method(), req.uri().path()) {
             (&Method::GET, "/") => Ok(Response::new(full("Ready."))),
 
             (&Method::POST, "/Plugin.Activate") => {
@@ -68,13 +157,15 @@ impl NetworkPluginService {
             (&Method::POST, "/NetworkDriver.DeleteEndpoint") => self.delete_endpoint(req).await,
 
             (&Method::POST, "/NetworkDriver.EndpointOperInfo") => {
-                Ok(Response::new(full(r#"{"Value": {}}"#)))
+                self.endpoint_oper_info(req).await
             }
 
             (&Method::POST, "/NetworkDriver.Join") => self.join(req).await,
 
             (&Method::POST, "/NetworkDriver.Leave") => self.leave(req).await,
 
+            (&Method::POST, "/Admin.ExportPeer") => self.export_peer(req).await,
+
             (&Method::POST, "/NetworkDriver.DiscoverNew") => {
                 let mut not_found = Response::new(empty());
                 *not_found.status_mut() = StatusCode::NOT_IMPLEMENTED;
@@ -92,7 +183,39 @@ impl NetworkPluginService {
                 *not_found.status_mut() = StatusCode::NOT_FOUND;
                 Ok(not_found)
             }
-        })
+        };
+        if let Err(err) = &result {
+            self.metrics.count_error(err.variant());
+        }
+        ok_or_error_response(result)
+    }
+
+    async fn serve_metrics(
+        self: Arc<Self>,
+        req: Request<hyper::body::Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        if (req.method(), req.uri().path()) != (&Method::GET, "/metrics") {
+            let mut not_found = Response::new(empty());
+            *not_found.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(not_found);
+        }
+        let stats = self.wg.stats().await;
+        let networks = match tokio::task::block_in_place(|| self.db.list_endpoints()) {
+            Ok(endpoints) => endpoints
+                .into_iter()
+                .map(|(endpoint_id, endpoint)| (endpoint_id, endpoint.network_id().to_owned()))
+                .collect(),
+            Err(err) => {
+                log::warn!(err:display; "Failed to list endpoints");
+                Default::default()
+            }
+        };
+        let mut response = Response::new(full(self.metrics.render(&stats, &networks)));
+        response.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+        );
+        Ok(response)
     }
 
     async fn create_network(
@@ -100,26 +223,83 @@ impl NetworkPluginService {
         req: Request<hyper::body::Incoming>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
         let body_bytes = req.collect().await?.to_bytes();
-        let db = self.db.clone();
-        tokio::task::spawn_blocking(move || {
+        let (req_body, network, lazy) = tokio::task::block_in_place(|| -> Result<_, Error> {
             let req_body: api::CreateNetworkRequest =
                 serde_json::from_slice(&body_bytes).map_err(Error::from)?;
 
-            let mut missing_fields = vec![];
-            if req_body.options.generic.config.is_none() {
-                missing_fields.push("wireguard-config");
+            let mut options = db::NetworkOptions::default();
+            if let Some(dns) = req_body.options.generic.dns {
+                options.dns = dns
+                    .parse()
+                    .map_err(|_| Error::InvalidOption("wireguard-dns", dns.to_owned()))?;
             }
-            if !missing_fields.is_empty() {
-                return Err(Error::MissingConfig(missing_fields));
+            if let Some(killswitch) = req_body.options.generic.killswitch {
+                options.killswitch = killswitch.parse().map_err(|_| {
+                    Error::InvalidOption("wireguard-killswitch", killswitch.to_owned())
+                })?;
             }
+            let lazy = match req_body.options.generic.config_lazy {
+                Some(lazy) => lazy
+                    .parse()
+                    .map_err(|_| Error::InvalidOption("wireguard-config-lazy", lazy.to_owned()))?,
+                None => false,
+            };
 
-            let config = req_body.options.generic.config.unwrap().to_owned();
+            let generic = &req_body.options.generic;
+            let inline_options = generic
+                .other
+                .iter()
+                .filter(|(option, _)| option.starts_with("wireguard."))
+                .map(|(option, value)| match value.as_str() {
+                    Some(value) => Ok((option.as_str(), value)),
+                    None => Err(Error::InvalidOption(
+                        "wireguard.*",
+                        format!("{option}={value}"),
+                    )),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let network = match (generic.config, generic.config_inline, &inline_options[..]) {
+                (Some(config), None, []) => db::Network::new(config.to_owned(), options),
+                (None, Some(encoded), []) => {
+                    let text = wg::decode_inline_config(encoded)
+                        .map_err(|err| Error::InvalidConfig("(inline)".to_owned(), err))?;
+                    db::Network::new_inline(text, options)
+                }
+                (None, None, [_, ..]) => {
+                    let text = wg::config_from_options(inline_options.iter().copied())
+                        .map_err(|err| Error::InvalidConfig("(inline)".to_owned(), err))?;
+                    db::Network::new_inline(text, options)
+                }
+                (None, None, []) => {
+                    return Err(Error::MissingConfig(vec![
+                        "wireguard-config",
+                        "wireguard-config-inline",
+                        "wireguard.*",
+                    ]))
+                }
+                _ => {
+                    return Err(Error::ConflictingOptions(vec![
+                        "wireguard-config",
+                        "wireguard-config-inline",
+                        "wireguard.*",
+                    ]))
+                }
+            };
 
-            let network_id = req_body.network_id;
+            Ok((req_body, network, lazy))
+        })?;
 
-            db.create_network(network_id, config).map_err(Error::from)
-        })
-        .await??;
+        // catch mistakes now, rather than when the first container starts;
+        // inline configurations are always checked, since they can't change
+        let source = network.config_source();
+        if !lazy || source.file_name().is_none() {
+            if let Err(err) = self.config_provider.load(source).await {
+                let name = source.file_name().unwrap_or("(inline)");
+                return Err(Error::InvalidConfig(name.to_owned(), err));
+            }
+        }
+
+        tokio::task::block_in_place(|| self.db.create_network(req_body.network_id, &network))?;
         Ok(Response::new(full("{}")))
     }
 
@@ -152,32 +332,77 @@ impl NetworkPluginService {
             }
         }
         let db = self.db.clone();
-        let (_req_body, network) = tokio::task::block_in_place(|| -> Result<_, Error> {
+        let (req_body, network) = tokio::task::block_in_place(|| -> Result<_, Error> {
             let req_body: api::CreateEndpointRequest =
                 serde_json::from_slice(&body_bytes).map_err(Error::from)?;
             let network = db.get_network(req_body.network_id).map_err(Error::from)?;
             Ok((req_body, network))
         })?;
-        let config_name = network.config();
-        let config = self.config_provider.get_config(config_name).await?;
-        if let Some(address) = config.address() {
-            let (address, address_ipv6) = match address.ip() {
-                std::net::IpAddr::V4(_) => (Some(address.to_string()), None),
-                std::net::IpAddr::V6(_) => (None, Some(address.to_string())),
-            };
-            let response_json = json!({
-                "Interface": {
-                    "Address": address,
-                    "AddressIPv6": address_ipv6,
-                    "MacAddress": null,
-                }
-            });
-            Ok(Response::new(full(response_json.to_string())))
+        let mut config = self.config_provider.load(network.config_source()).await?;
+        if let Some(pool) = config.address_pool().cloned() {
+            let private_key = wg::Key::generate();
+            let address = self.allocate_endpoint(&req_body, &pool, &private_key)?;
+            log::info!(
+                endpoint_id:display = req_body.endpoint_id,
+                public_key:display = private_key.public_key(),
+                address:display;
+                "Generated endpoint key"
+            );
+            config.set_identity(private_key, vec![address]);
         } else {
-            Ok(Response::new(full(r#"{"Interface":{}}"#)))
+            let endpoint = db::Endpoint::new(
+                req_body.network_id,
+                config.addresses().iter().map(ToString::to_string).collect(),
+            );
+            tokio::task::block_in_place(|| db.put_endpoint(req_body.endpoint_id, &endpoint))?;
+        }
+        match config.primary_addresses() {
+            (None, None) => Ok(Response::new(full(r#"{"Interface":{}}"#))),
+            (address, address_ipv6) => {
+                let response_json = json!({
+                    "Interface": {
+                        "Address": address.map(ToString::to_string),
+                        "AddressIPv6": address_ipv6.map(ToString::to_string),
+                        "MacAddress": null,
+                    }
+                });
+                Ok(Response::new(full(response_json.to_string())))
+            }
         }
     }
 
+    /// Record an endpoint with a generated key, and the first address of the
+    /// pool that no other endpoint of the network uses.
+    fn allocate_endpoint(
+        &self,
+        req_body: &api::CreateEndpointRequest,
+        pool: &wg::CidrAddress,
+        private_key: &wg::Key,
+    ) -> Result<wg::CidrAddress, Error> {
+        let network_id = req_body.network_id.to_string();
+        tokio::task::block_in_place(|| {
+            self.db
+                .put_endpoint_with(req_body.endpoint_id, |endpoints| {
+                    let used: Vec<_> = endpoints
+                        .iter()
+                        .filter(|(_, endpoint)| endpoint.network_id() == network_id)
+                        .flat_map(|(_, endpoint)| endpoint.addresses())
+                        .filter_map(|address| address.parse::<wg::CidrAddress>().ok())
+                        .map(|address| *address.ip())
+                        .collect();
+                    let address = pool
+                        .allocate(&used)
+                        .ok_or_else(|| Error::PoolExhausted(pool.to_string()))?;
+                    let endpoint = db::Endpoint::new_generated(
+                        req_body.network_id,
+                        address.to_string(),
+                        private_key.clone(),
+                    );
+                    Ok((endpoint, address))
+                })
+        })
+    }
+
     async fn delete_endpoint(
         &self,
         req: Request<hyper::body::Incoming>,
@@ -188,9 +413,96 @@ impl NetworkPluginService {
                 log::trace!(body = s; "delete endpoint request");
             }
         }
+        tokio::task::block_in_place(|| -> Result<_, Error> {
+            let req_body: api::DeleteEndpointRequest =
+                serde_json::from_slice(&body_bytes).map_err(Error::from)?;
+            self.db
+                .delete_endpoint(req_body.endpoint_id)
+                .map_err(Error::from)
+        })?;
         Ok(Response::new(full("{}")))
     }
 
+    async fn endpoint_oper_info(
+        &self,
+        req: Request<hyper::body::Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+        let body_bytes = req.collect().await?.to_bytes();
+        if log_enabled!(log::Level::Trace) {
+            if let Ok(s) = std::str::from_utf8(&body_bytes) {
+                log::trace!(body = s; "endpoint oper info request");
+            }
+        }
+        let req_body: api::EndpointOperInfoRequest = serde_json::from_slice(&body_bytes)?;
+        let endpoint =
+            match tokio::task::block_in_place(|| self.db.get_endpoint(req_body.endpoint_id)) {
+                Ok(endpoint) => Some(endpoint),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+        let stats = match self.wg.endpoint_stats(req_body.endpoint_id).await {
+            Ok(stats) => stats,
+            Err(err) => {
+                // the static information is still useful without statistics
+                log::warn!(
+                    err:display,
+                    endpoint_id:display = req_body.endpoint_id;
+                    "Failed to read interface statistics"
+                );
+                None
+            }
+        };
+
+        let mut value = serde_json::Map::new();
+        if let Some(endpoint) = &endpoint {
+            if let Some(interface) = endpoint.interface() {
+                value.insert("Interface".to_owned(), json!(interface));
+            }
+            value.insert("Addresses".to_owned(), json!(endpoint.addresses()));
+            if let Some(config_hash) = endpoint.config_hash() {
+                value.insert("ConfigHash".to_owned(), json!(config_hash));
+            }
+        }
+        let public_key = match &stats {
+            Some(stats) => stats.public_key.clone(),
+            None => match self.wg.endpoint_public_key(req_body.endpoint_id).await {
+                Some(public_key) => Some(public_key),
+                // not joined to a sandbox, but a generated key is already known
+                None => endpoint
+                    .as_ref()
+                    .and_then(db::Endpoint::private_key)
+                    .map(wg::Key::public_key),
+            },
+        };
+        if let Some(public_key) = public_key {
+            value.insert("PublicKey".to_owned(), json!(public_key.to_string()));
+        }
+        if let Some(stats) = stats {
+            let peers: Vec<_> = stats
+                .peers
+                .iter()
+                .map(|peer| {
+                    json!({
+                        "PublicKey": peer.public_key.to_string(),
+                        "Endpoint": peer.endpoint.map(|endpoint| endpoint.to_string()),
+                        "LatestHandshake": peer
+                            .last_handshake
+                            .map(|time| humantime::format_rfc3339_seconds(time).to_string()),
+                        "TransferRx": peer.rx_bytes,
+                        "TransferTx": peer.tx_bytes,
+                        "Stale": peer.stale,
+                    })
+                })
+                .collect();
+            value.insert("Interface".to_owned(), json!(stats.if_name));
+            value.insert("ListenPort".to_owned(), json!(stats.listen_port));
+            value.insert("Config".to_owned(), json!(stats.config_name));
+            value.insert("Peers".to_owned(), json!(peers));
+        }
+        let response_json = json!({ "Value": value });
+        Ok(Response::new(full(response_json.to_string())))
+    }
+
     async fn join(
         &self,
         req: Request<hyper::body::Incoming>,
@@ -202,20 +514,76 @@ impl NetworkPluginService {
             }
         }
         let db = self.db.clone();
-        let (network, endpoint_id) = tokio::task::block_in_place(|| -> Result<_, Error> {
+        let (network, req_body) = tokio::task::block_in_place(|| -> Result<_, Error> {
             let req_body: api::JoinRequest =
                 serde_json::from_slice(&body_bytes).map_err(Error::from)?;
             Ok((
                 db.get_network(req_body.network_id).map_err(Error::from)?,
-                req_body.endpoint_id,
+                req_body,
             ))
         })?;
-        let config_name = network.config();
-        let config = self.config_provider.get_config(config_name).await?;
-        let if_name = self
+        let source = network.config_source();
+        let mut config = self.config_provider.load(source).await?;
+        let mut endpoint =
+            tokio::task::block_in_place(|| match db.get_endpoint(req_body.endpoint_id) {
+                Ok(endpoint) => Ok(endpoint),
+                // created by an older version of the plugin
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(db::Endpoint::new(
+                    req_body.network_id,
+                    config.addresses().iter().map(ToString::to_string).collect(),
+                )),
+                Err(err) => Err(err),
+            })?;
+        set_endpoint_identity(&mut config, &endpoint)?;
+        let interface = self
             .wg
-            .create_interface(endpoint_id, config.clone())
+            .create_interface(req_body.endpoint_id, source.file_name(), config.clone())
             .await?;
+        let sandbox_key = req_body.sandbox_key.as_ref().to_owned();
+        endpoint.join(
+            interface.name().to_owned(),
+            sandbox_key.clone(),
+            config.digest(),
+        );
+        let result =
+            tokio::task::block_in_place(|| db.put_endpoint(req_body.endpoint_id, &endpoint));
+        if let Err(err) = result {
+            self.wg.delete_interface(req_body.endpoint_id).await;
+            return Err(err.into());
+        }
+        let killswitch = network.options().killswitch;
+        if killswitch {
+            // installed before replying, so that the container never starts
+            // without it
+            let endpoint_id = req_body.endpoint_id.to_string();
+            if let Err(err) = self
+                .killswitches
+                .install(endpoint_id, sandbox_key.clone())
+                .await
+            {
+                self.wg.delete_interface(req_body.endpoint_id).await;
+                let _ = tokio::task::block_in_place(|| {
+                    db.update_endpoint(req_body.endpoint_id, db::Endpoint::leave)
+                });
+                return Err(err.into());
+            }
+        }
+        let setup = SandboxSetup {
+            db: self.db.clone(),
+            wg: self.wg.clone(),
+            sandbox_key,
+            endpoint_id: req_body.endpoint_id.to_string(),
+            if_name: interface.name().to_owned(),
+            index: interface.index(),
+            secondary_addresses: config.secondary_addresses().cloned().collect(),
+            resolv_conf: resolv_conf(&config, network.options()),
+            killswitches: killswitch.then(|| self.killswitches.clone()),
+        };
+        {
+            let mut sandbox_setups = self.sandbox_setups.lock().unwrap();
+            while sandbox_setups.try_join_next().is_some() {}
+            sandbox_setups.spawn(setup.apply());
+        }
         let static_routes: Vec<_> = config
             .routes()
             .map(|route| {
@@ -227,7 +595,7 @@ impl NetworkPluginService {
             .collect();
         let response_json = json!({
             "InterfaceName": {
-                "SrcName": if_name,
+                "SrcName": interface.name(),
                 "DstPrefix": "wg",
             },
             "StaticRoutes": static_routes,
@@ -237,6 +605,68 @@ impl NetworkPluginService {
         Ok(Response::new(full(response_json.to_string())))
     }
 
+    /// Export `[Peer]` sections that the other side of the tunnels can use to
+    /// add the endpoints of a network, or a single endpoint, as peers.
+    async fn export_peer(
+        &self,
+        req: Request<hyper::body::Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+        let body_bytes = req.collect().await?.to_bytes();
+        let req_body: api::ExportPeerRequest = serde_json::from_slice(&body_bytes)?;
+        let db = self.db.clone();
+        let (network_id, endpoints) = tokio::task::block_in_place(|| -> Result<_, Error> {
+            match (req_body.network_id, req_body.endpoint_id) {
+                (None, Some(endpoint_id)) => {
+                    let endpoint = db.get_endpoint(endpoint_id)?;
+                    let network_id = endpoint.network_id().to_owned();
+                    Ok((network_id, vec![(endpoint_id.to_string(), endpoint)]))
+                }
+                (Some(network_id), None) => {
+                    let network_id = network_id.to_string();
+                    let mut endpoints: Vec<_> = db
+                        .list_endpoints()?
+                        .into_iter()
+                        .filter(|(_, endpoint)| endpoint.network_id() == network_id)
+                        .collect();
+                    endpoints.sort_by(|(a, _), (b, _)| a.cmp(b));
+                    Ok((network_id, endpoints))
+                }
+                (None, None) => Err(Error::MissingConfig(vec!["NetworkID", "EndpointID"])),
+                (Some(_), Some(_)) => {
+                    Err(Error::ConflictingOptions(vec!["NetworkID", "EndpointID"]))
+                }
+            }
+        })?;
+        let network_id = api::NetworkId::new(&network_id);
+        let network = tokio::task::block_in_place(|| db.get_network(network_id))?;
+        let config = self.config_provider.load(network.config_source()).await?;
+
+        let mut text = String::new();
+        if config.address_pool().is_none() {
+            // all the endpoints share the same key and addresses
+            if let Some(section) = config.export_peer(&format!("network {network_id}")) {
+                text.push_str(&section);
+            }
+        } else {
+            for (endpoint_id, endpoint) in &endpoints {
+                let mut config = config.clone();
+                set_endpoint_identity(&mut config, endpoint)?;
+                if let Some(section) = config.export_peer(&format!("endpoint {endpoint_id}")) {
+                    if !text.is_empty() {
+                        text.push('\n');
+                    }
+                    text.push_str(&section);
+                }
+            }
+        }
+        let mut response = Response::new(full(text));
+        response.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("text/plain"),
+        );
+        Ok(response)
+    }
+
     async fn leave(
         &self,
         req: Request<hyper::body::Incoming>,
@@ -252,11 +682,86 @@ impl NetworkPluginService {
                 serde_json::from_slice(&body_bytes).map_err(Error::from)?;
             Ok(req_body.endpoint_id)
         })?;
+        if let Err(err) = tokio::task::block_in_place(|| {
+            self.db.update_endpoint(endpoint_id, db::Endpoint::leave)
+        }) {
+            log::error!(err:display, endpoint_id:display; "Failed to update endpoint");
+        }
+        if let Err(err) = self.killswitches.remove(&endpoint_id.to_string()).await {
+            log::error!(err:display, endpoint_id:display; "Failed to remove kill switch");
+        }
         self.wg.delete_interface(endpoint_id).await;
         Ok(Response::new(full("{}")))
     }
 }
 
+/// Configuration that is applied after Docker has moved the interface into
+/// the sandbox. This also tells `wg` where the interface went, so that it can
+/// still be reconfigured.
+struct SandboxSetup {
+    db: Arc<db::Db>,
+    wg: Arc<wg::Wg>,
+    sandbox_key: std::path::PathBuf,
+    endpoint_id: String,
+    if_name: String,
+    index: u32,
+    secondary_addresses: Vec<wg::CidrAddress>,
+    resolv_conf: Option<String>,
+    killswitches: Option<Arc<sandbox::KillSwitches>>,
+}
+
+impl SandboxSetup {
+    async fn apply(self) {
+        let result = async {
+            let sandbox = sandbox::Sandbox::open(&self.sandbox_key).await?;
+            let index = sandbox.wait_for_link(self.index, &self.if_name).await?;
+            self.wg
+                .attach_sandbox(&self.endpoint_id, self.sandbox_key.clone(), index)
+                .await;
+            let endpoint_id = api::EndpointId::new(&self.endpoint_id);
+            if let Err(err) = tokio::task::block_in_place(|| {
+                self.db
+                    .update_endpoint(endpoint_id, |endpoint| endpoint.set_sandbox_index(index))
+            }) {
+                log::error!(err:display, endpoint_id:display; "Failed to update endpoint");
+            }
+            sandbox
+                .add_addresses(index, &self.secondary_addresses)
+                .await?;
+            if let Some(killswitches) = &self.killswitches {
+                killswitches.allow(&self.endpoint_id, index).await?;
+            }
+            if let Some(resolv_conf) = &self.resolv_conf {
+                sandbox::write_resolv_conf(&self.sandbox_key, resolv_conf.clone()).await?;
+            }
+            Ok::<_, sandbox::Error>(())
+        }
+        .await;
+        if let Err(err) = result {
+            log::error!(
+                err:display,
+                if_name = self.if_name.as_str(),
+                sandbox:display = self.sandbox_key.display();
+                "Failed to configure interface in sandbox"
+            );
+        }
+    }
+}
+
+fn resolv_conf(config: &wg::Config, options: &db::NetworkOptions) -> Option<String> {
+    match options.dns {
+        db::DnsMode::Docker => None,
+        db::DnsMode::Override if config.dns_servers().is_empty() => {
+            log::warn!("wireguard-dns=override is set, but the configuration has no DNS");
+            None
+        }
+        db::DnsMode::Override => Some(sandbox::resolv_conf(
+            config.dns_servers(),
+            config.dns_search(),
+        )),
+    }
+}
+
 fn empty() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
@@ -274,10 +779,36 @@ enum Error {
     SerdeJson(serde_json::Error),
     Io(std::io::Error),
     Wg(WgError),
+    Sandbox(sandbox::Error),
     MissingConfig(Vec<&'static str>),
+    ConflictingOptions(Vec<&'static str>),
+    InvalidOption(&'static str, String),
+    InvalidConfig(String, WgError),
+    PoolExhausted(String),
+    MissingEndpointKey,
     Abort,
 }
 
+impl Error {
+    /// Name of the variant, for metrics.
+    fn variant(&self) -> &'static str {
+        match self {
+            Error::Hyper(_) => "Hyper",
+            Error::SerdeJson(_) => "SerdeJson",
+            Error::Io(_) => "Io",
+            Error::Wg(_) => "Wg",
+            Error::Sandbox(_) => "Sandbox",
+            Error::MissingConfig(_) => "MissingConfig",
+            Error::ConflictingOptions(_) => "ConflictingOptions",
+            Error::InvalidOption(..) => "InvalidOption",
+            Error::InvalidConfig(..) => "InvalidConfig",
+            Error::PoolExhausted(_) => "PoolExhausted",
+            Error::MissingEndpointKey => "MissingEndpointKey",
+            Error::Abort => "Abort",
+        }
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
         Error::SerdeJson(e)
@@ -302,12 +833,37 @@ impl From<tokio::task::JoinError> for Error {
     }
 }
 
+impl From<sandbox::Error> for Error {
+    fn from(e: sandbox::Error) -> Self {
+        Error::Sandbox(e)
+    }
+}
+
 impl From<WgError> for Error {
     fn from(e: WgError) -> Self {
         Error::Wg(e)
     }
 }
 
+/// Use the generated key and the allocated address of an endpoint, if the
+/// configuration has an address pool.
+fn set_endpoint_identity(config: &mut wg::Config, endpoint: &db::Endpoint) -> Result<(), Error> {
+    if config.address_pool().is_none() {
+        return Ok(());
+    }
+    let private_key = endpoint
+        .private_key()
+        .cloned()
+        .ok_or(Error::MissingEndpointKey)?;
+    let addresses = endpoint
+        .addresses()
+        .iter()
+        .filter_map(|address| address.parse().ok())
+        .collect();
+    config.set_identity(private_key, addresses);
+    Ok(())
+}
+
 fn ok_or_error_response(
     result: Result<Response<BoxBody<Bytes, hyper::Error>>, Error>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
@@ -323,13 +879,40 @@ fn ok_or_error_response(
             error_response(&message, StatusCode::INTERNAL_SERVER_ERROR)
         }
         Err(Error::MissingConfig(fields)) => {
-            let message = format!("Missing configuration options: {}", &fields.join(", "));
+            let message = format!("Missing configuration, use one of: {}", &fields.join(", "));
+            error_response(&message, StatusCode::BAD_REQUEST)
+        }
+        Err(Error::ConflictingOptions(fields)) => {
+            let message = format!(
+                "Only one of these options can be used: {}",
+                fields.join(", ")
+            );
             error_response(&message, StatusCode::BAD_REQUEST)
         }
+        Err(Error::InvalidOption(name, value)) => {
+            let message = format!("Invalid value for option {name}: {value}");
+            error_response(&message, StatusCode::BAD_REQUEST)
+        }
+        Err(Error::InvalidConfig(name, e)) => {
+            let message = format!("Invalid configuration {name}: {e}");
+            error_response(&message, StatusCode::BAD_REQUEST)
+        }
+        Err(Error::PoolExhausted(pool)) => {
+            let message = format!("No free address in AddressPool {pool}");
+            error_response(&message, StatusCode::CONFLICT)
+        }
+        Err(Error::MissingEndpointKey) => error_response(
+            "Endpoint has no generated key, it was created before the configuration had an AddressPool",
+            StatusCode::BAD_REQUEST,
+        ),
         Err(Error::Wg(e)) => {
             let message = format!("error while configuring wireguard interface: {e}");
             error_response(&message, StatusCode::INTERNAL_SERVER_ERROR)
         }
+        Err(Error::Sandbox(e)) => {
+            let message = format!("error while configuring container sandbox: {e}");
+            error_response(&message, StatusCode::INTERNAL_SERVER_ERROR)
+        }
         Err(Error::Abort) => error_response("aborted", StatusCode::INTERNAL_SERVER_ERROR),
     })
 }
@@ -359,42 +942,176 @@ async fn shutdown_signal() {
     };
 }
 
+/// Apply configuration changes to the interfaces that use them.
+async fn reload_configs(service: Arc<NetworkPluginService>, mut watcher: wg::ConfigWatcher) {
+    while let Some(config_name) = watcher.next().await {
+        match service.config_provider.get_config(&config_name).await {
+            Ok(config) => service.wg.reload(&config_name, config).await,
+            Err(err) => log::warn!(
+                err:display,
+                config_name = config_name.as_str();
+                "Not reloading invalid configuration"
+            ),
+        }
+    }
+}
+
+/// How often hostnames in peer endpoints are resolved again.
+const RERESOLVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(120);
+
+async fn reresolve_endpoints(wg: Arc<wg::Wg>) {
+    let mut interval = tokio::time::interval(RERESOLVE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        wg.reresolve_endpoints().await;
+    }
+}
+
+/// Serve metrics on a unix socket (if the address is a path) or on TCP.
+async fn metrics_server(
+    address: String,
+    service: Arc<NetworkPluginService>,
+) -> Result<(), std::io::Error> {
+    fn serve_connection<S>(stream: S, service: Arc<NetworkPluginService>)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |req| service.clone().serve_metrics(req)),
+                )
+                .await
+            {
+                log::error!("Error serving metrics connection: {:?}", err);
+            }
+        });
+    }
+
+    if address.contains('/') {
+        let listener = bind_socket(Path::new(&address))?;
+        log::info!(address = address.as_str(); "Serving metrics");
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => serve_connection(stream, service.clone()),
+                Err(err) => log::error!(err:display; "Failed to accept metrics connection"),
+            }
+        }
+    } else {
+        let listener = tokio::net::TcpListener::bind(&address).await?;
+        log::info!(address = address.as_str(); "Serving metrics");
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => serve_connection(stream, service.clone()),
+                Err(err) => log::error!(err:display; "Failed to accept metrics connection"),
+            }
+        }
+    }
+}
+
+/// Bind the plugin socket. A socket file left behind by a run that didn't
+/// shut down cleanly is removed, but not one that still accepts connections.
+fn bind_socket(path: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match UnixListener::bind(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => {
+            let is_socket = std::fs::symlink_metadata(path)
+                .is_ok_and(|metadata| metadata.file_type().is_socket());
+            let stale = is_socket
+                && std::os::unix::net::UnixStream::connect(path)
+                    .is_err_and(|err| err.kind() == std::io::ErrorKind::ConnectionRefused);
+            if !stale {
+                return Err(err);
+            }
+            log::info!(path:display = path.display(); "Removing stale socket file");
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
 async fn server(
-    path: &str,
+    listener: UnixListener,
     service: Arc<NetworkPluginService>,
+    shutdown_timeout: std::time::Duration,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let listener = UnixListener::bind(path)?;
-    log::info!(path; "Listening on socket");
     let mut shutdown = std::pin::pin!(shutdown_signal());
+    let graceful = GracefulShutdown::new();
 
     loop {
         tokio::select! {
             Ok((stream, _addr)) = listener.accept() => {
                 let io = TokioIo::new(stream);
                 let service_ref = service.clone();
+                let connection = http1::Builder::new()
+                    .serve_connection(io, service_fn(move |req| service_ref.clone().serve(req)));
+                let connection = graceful.watch(connection);
                 tokio::task::spawn(async move {
-                    if let Err(err) = http1::Builder::new()
-                        .serve_connection(io, service_fn(move |req| service_ref.clone().serve(req)))
-                        .await
-                    {
+                    if let Err(err) = connection.await {
                         log::error!("Error serving connection: {:?}", err);
                     }
                 });
             }
             _ = &mut shutdown => {
                 log::info!("Shutting down...");
-                break Ok(());
+                break;
             }
         }
     }
+
+    systemd::notify("STOPPING=1");
+    // stop accepting, and let the requests in flight complete, including the
+    // sandbox setup that goes on after a Join response
+    drop(listener);
+    let drain = async {
+        graceful.shutdown().await;
+        let mut sandbox_setups = std::mem::take(&mut *service.sandbox_setups.lock().unwrap());
+        while sandbox_setups.join_next().await.is_some() {}
+    };
+    match tokio::time::timeout(shutdown_timeout, drain).await {
+        Ok(()) => log::info!("All requests completed"),
+        Err(_) => log::warn!(
+            timeout:? = shutdown_timeout;
+            "Timed out waiting for requests to complete"
+        ),
+    }
+    Ok(())
 }
 
 fn main() -> ExitCode {
-    if logging::configure_logging().is_err() {
+    let cli = settings::Cli::parse();
+    // the settings of the daemon don't need to be valid to check files
+    if let Some(settings::Command::Check { mut paths }) = cli.command {
+        if paths.is_empty() {
+            match settings::Settings::conf_dir(cli.config.as_deref(), cli.options) {
+                Ok(conf_dir) => paths.push(conf_dir),
+                Err(err) => {
+                    eprintln!("Error: {err}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        return check::run(&paths);
+    }
+
+    let settings = match settings::Settings::load(cli.config.as_deref(), cli.options) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if logging::configure_logging(&settings.logging).is_err() {
         return ExitCode::FAILURE;
     }
-    let netns_options = netns::NetworkNamespaceOptions::from_env();
-    if netns::enter_net_namespace(&netns_options).is_err() {
+    settings.log();
+    let inherited_listener = systemd::take_listener();
+    if netns::enter_net_namespace(&settings.netns).is_err() {
         return ExitCode::FAILURE;
     }
     let rt = tokio::runtime::Builder::new_multi_thread()
@@ -403,7 +1120,7 @@ fn main() -> ExitCode {
         .thread_name("worker")
         .build()
         .unwrap();
-    match rt.block_on(async_main()) {
+    match rt.block_on(async_main(settings, inherited_listener)) {
         Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
             log::error!("Error: {:?}", e);
@@ -412,17 +1129,80 @@ fn main() -> ExitCode {
     }
 }
 
-async fn async_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let socket_path = "/run/docker/plugins/wireguard.sock";
-    let db_path = "wireguard_db";
-    let conf_path = "wireguard_conf";
-    let config_provider = wg::ConfigProvider::new_file(conf_path.into());
+async fn async_main(
+    settings: settings::Settings,
+    inherited_listener: Option<std::os::unix::net::UnixListener>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config_provider = wg::ConfigProvider::new_file(settings.conf_dir);
+
+    let service = Arc::new(NetworkPluginService::new(
+        &settings.db_dir,
+        config_provider,
+        settings.interface_prefix,
+    )?);
+
+    if let Err(err) = service.wg.reconcile().await {
+        log::error!(err:display; "Failed to clean up orphaned interfaces");
+    }
+    if let Err(err) = service.restore_interfaces().await {
+        log::error!(err:display; "Failed to restore interfaces");
+    }
+    if let Err(err) = tokio::task::block_in_place(|| service.restore_killswitches()) {
+        log::error!(err:display; "Failed to restore kill switches");
+    }
+
+    match service.config_provider.watch() {
+        Ok(Some(watcher)) => {
+            tokio::spawn(reload_configs(service.clone(), watcher));
+        }
+        Ok(None) => {}
+        Err(err) => {
+            log::warn!(err:display; "Cannot watch configuration, changes will not be applied");
+        }
+    }
+
+    tokio::spawn(reresolve_endpoints(service.wg.clone()));
+
+    let watchdog = settings.watchdog;
+    let wg = service.wg.clone();
+    tokio::spawn(async move { wg.watch_handshakes(watchdog).await });
+
+    if let Some(address) = settings.metrics_listen {
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(err) = metrics_server(address, service).await {
+                log::error!(err:display; "Failed to serve metrics");
+            }
+        });
+    }
+
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(systemd::keep_alive(interval));
+    }
+
+    // the socket file of an inherited listener belongs to the service manager
+    let owns_socket_file = inherited_listener.is_none();
+    let listener = match inherited_listener {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            log::info!("Listening on socket passed by the service manager");
+            UnixListener::from_std(listener)?
+        }
+        None => {
+            let listener = bind_socket(&settings.socket)?;
+            log::info!(path:display = settings.socket.display(); "Listening on socket");
+            listener
+        }
+    };
 
-    let service = Arc::new(NetworkPluginService::new(db_path, config_provider)?);
+    systemd::notify("READY=1");
+    server(listener, service.clone(), settings.shutdown_timeout).await?;
 
-    server(socket_path, service).await?;
+    // interfaces are left in place, so that containers keep their connection
+    // while the plugin restarts
+    service.wg.persist().await;
 
-    if std::fs::remove_file(socket_path).is_ok() {
+    if owns_socket_file && std::fs::remove_file(&settings.socket).is_ok() {
         log::info!("Removed socket file");
     }
 
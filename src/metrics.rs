@@ -0,0 +1,331 @@
+//! Prometheus metrics, in the text exposition format.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::wg::{InterfaceStats, PeerStats};
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Name, type, help text and value of a metric family, by interface.
+type InterfaceFamily = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&InterfaceStats) -> u64,
+);
+/// Name, type, help text and value of a metric family, by peer.
+type PeerFamily = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&PeerStats, SystemTime) -> Option<u64>,
+);
+
+/// Routes of the network driver API that are counted. Requests for other
+/// paths are not, so that clients can't add labels at will.
+const ROUTES: &[&str] = &[
+    "NetworkDriver.GetCapabilities",
+    "NetworkDriver.CreateNetwork",
+    "NetworkDriver.DeleteNetwork",
+    "NetworkDriver.CreateEndpoint",
+    "NetworkDriver.DeleteEndpoint",
+    "NetworkDriver.EndpointOperInfo",
+    "NetworkDriver.Join",
+    "NetworkDriver.Leave",
+    "NetworkDriver.DiscoverNew",
+    "NetworkDriver.DiscoverDelete",
+];
+
+/// Counters of the plugin API.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Requests by route, e.g. `NetworkDriver.Join`.
+    requests: Mutex<BTreeMap<&'static str, u64>>,
+    /// Error responses by `Error` variant.
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    /// Count a request for `route`, if it is one of [`ROUTES`].
+    pub(crate) fn count_request(&self, route: &str) {
+        if let Some(route) = ROUTES.iter().find(|known| **known == route) {
+            *self.requests.lock().unwrap().entry(route).or_default() += 1;
+        }
+    }
+
+    pub(crate) fn count_error(&self, variant: &'static str) {
+        *self.errors.lock().unwrap().entry(variant).or_default() += 1;
+    }
+
+    /// Render the plugin counters, and the statistics of the interfaces.
+    /// `networks` maps endpoint ids to network ids.
+    pub(crate) fn render(
+        &self,
+        interfaces: &[InterfaceStats],
+        networks: &HashMap<String, String>,
+    ) -> String {
+        let mut out = String::new();
+
+        let name = "wireguard_plugin_requests_total";
+        header(&mut out, name, "counter", "Requests, by plugin API route.");
+        for (route, count) in self.requests.lock().unwrap().iter() {
+            sample(&mut out, name, &[("route", route.to_string())], *count);
+        }
+
+        let name = "wireguard_plugin_errors_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Error responses, by kind of error.",
+        );
+        for (variant, count) in self.errors.lock().unwrap().iter() {
+            sample(&mut out, name, &[("kind", variant.to_string())], *count);
+        }
+
+        let interfaces: Vec<_> = interfaces
+            .iter()
+            .map(|interface| (interface, interface_labels(interface, networks)))
+            .collect();
+
+        let interface_families: [InterfaceFamily; 3] = [
+            (
+                "wireguard_interface_peers",
+                "gauge",
+                "Number of peers of the interface.",
+                |interface| interface.peers.len() as u64,
+            ),
+            (
+                "wireguard_interface_receive_bytes_total",
+                "counter",
+                "Bytes received from all peers of the interface.",
+                |interface| interface.peers.iter().map(|peer| peer.rx_bytes).sum(),
+            ),
+            (
+                "wireguard_interface_transmit_bytes_total",
+                "counter",
+                "Bytes sent to all peers of the interface.",
+                |interface| interface.peers.iter().map(|peer| peer.tx_bytes).sum(),
+            ),
+        ];
+        for (name, kind, help, value) in interface_families {
+            header(&mut out, name, kind, help);
+            for (interface, labels) in &interfaces {
+                sample(&mut out, name, labels, value(interface));
+            }
+        }
+
+        let name = "wireguard_peer_info";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Peers, with their current endpoint.",
+        );
+        for (interface, labels) in &interfaces {
+            for peer in &interface.peers {
+                let mut labels = peer_labels(labels, peer);
+                let endpoint = peer.endpoint.map(|endpoint| endpoint.to_string());
+                labels.push(("endpoint", endpoint.unwrap_or_default()));
+                sample(&mut out, name, &labels, 1);
+            }
+        }
+
+        let now = SystemTime::now();
+        let peer_families: [PeerFamily; 4] = [
+            (
+                "wireguard_peer_receive_bytes_total",
+                "counter",
+                "Bytes received from the peer.",
+                |peer, _| Some(peer.rx_bytes),
+            ),
+            (
+                "wireguard_peer_transmit_bytes_total",
+                "counter",
+                "Bytes sent to the peer.",
+                |peer, _| Some(peer.tx_bytes),
+            ),
+            (
+                "wireguard_peer_last_handshake_age_seconds",
+                "gauge",
+                "Seconds since the last handshake with the peer, if there was one.",
+                |peer, now| {
+                    let last_handshake = peer.last_handshake?;
+                    Some(
+                        now.duration_since(last_handshake)
+                            .unwrap_or_default()
+                            .as_secs(),
+                    )
+                },
+            ),
+            (
+                "wireguard_peer_stale",
+                "gauge",
+                "Whether the handshake with the peer is overdue.",
+                |peer, _| Some(u64::from(peer.stale)),
+            ),
+        ];
+        for (name, kind, help, value) in peer_families {
+            header(&mut out, name, kind, help);
+            for (interface, labels) in &interfaces {
+                for peer in &interface.peers {
+                    if let Some(value) = value(peer, now) {
+                        sample(&mut out, name, &peer_labels(labels, peer), value);
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+fn interface_labels(interface: &InterfaceStats, networks: &HashMap<String, String>) -> Labels {
+    let network_id = networks.get(&interface.endpoint_id);
+    vec![
+        ("network_id", network_id.cloned().unwrap_or_default()),
+        ("endpoint_id", interface.endpoint_id.clone()),
+        ("config", interface.config_name.clone().unwrap_or_default()),
+        ("interface", interface.if_name.clone()),
+    ]
+}
+
+fn peer_labels(interface_labels: &Labels, peer: &PeerStats) -> Labels {
+    let mut labels = interface_labels.clone();
+    labels.push(("public_key", peer.public_key.to_string()));
+    labels
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&'static str, String)], value: u64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (label, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, "{label}=\"").unwrap();
+            for c in value.chars() {
+                match c {
+                    '\\' => out.push_str("\\\\"),
+                    '"' => out.push_str("\\\""),
+                    '\n' => out.push_str("\\n"),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        out.push('}');
+    }
+    writeln!(out, " {value}").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wg::Key;
+
+    fn interface(
+        endpoint_id: &str,
+        config_name: Option<&str>,
+        peers: Vec<PeerStats>,
+    ) -> InterfaceStats {
+        InterfaceStats {
+            endpoint_id: endpoint_id.to_owned(),
+            if_name: format!("wgdkr-{endpoint_id}"),
+            config_name: config_name.map(str::to_owned),
+            public_key: None,
+            listen_port: 51820,
+            peers,
+        }
+    }
+
+    fn peer(rx_bytes: u64, tx_bytes: u64) -> PeerStats {
+        PeerStats {
+            public_key: Key::from([1; 32]),
+            endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+            rx_bytes,
+            tx_bytes,
+            last_handshake: None,
+            stale: true,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.count_request("NetworkDriver.Join");
+        metrics.count_request("NetworkDriver.Join");
+        metrics.count_request("NetworkDriver.Leave");
+        metrics.count_request("NetworkDriver.Unknown");
+        metrics.count_request("Plugin.Activate");
+        metrics.count_error("PoolExhausted");
+
+        let interfaces = [
+            interface("e1", Some("mynet"), vec![peer(10, 20), peer(1, 2)]),
+            interface("e2", None, Vec::new()),
+        ];
+        let networks = HashMap::from([("e1".to_owned(), "n1".to_owned())]);
+        let out = metrics.render(&interfaces, &networks);
+        let lines: Vec<_> = out.lines().collect();
+
+        let key = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+        let e1 = r#"network_id="n1",endpoint_id="e1",config="mynet",interface="wgdkr-e1""#;
+        let e2 = r#"network_id="",endpoint_id="e2",config="",interface="wgdkr-e2""#;
+        for expected in [
+            "# HELP wireguard_plugin_requests_total Requests, by plugin API route.",
+            "# TYPE wireguard_plugin_requests_total counter",
+            r#"wireguard_plugin_requests_total{route="NetworkDriver.Join"} 2"#,
+            r#"wireguard_plugin_requests_total{route="NetworkDriver.Leave"} 1"#,
+            r#"wireguard_plugin_errors_total{kind="PoolExhausted"} 1"#,
+            &format!("wireguard_interface_peers{{{e1}}} 2"),
+            &format!("wireguard_interface_peers{{{e2}}} 0"),
+            &format!("wireguard_interface_receive_bytes_total{{{e1}}} 11"),
+            &format!("wireguard_interface_transmit_bytes_total{{{e1}}} 22"),
+            &format!(
+                r#"wireguard_peer_info{{{e1},public_key="{key}",endpoint="192.0.2.1:51820"}} 1"#
+            ),
+            &format!(r#"wireguard_peer_receive_bytes_total{{{e1},public_key="{key}"}} 10"#),
+            &format!(r#"wireguard_peer_stale{{{e1},public_key="{key}"}} 1"#),
+        ] {
+            assert!(lines.contains(&expected), "missing {expected:?} in:\n{out}");
+        }
+
+        // only known routes are counted, and peers without a handshake have no age
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.starts_with("wireguard_plugin_requests_total{"))
+                .count(),
+            2
+        );
+        assert!(!out.contains("wireguard_peer_last_handshake_age_seconds{"));
+    }
+
+    #[test]
+    fn test_label_escaping() {
+        let mut out = String::new();
+        let labels = [
+            ("plain", "mynet".to_owned()),
+            ("quoted", r#"a "b" c"#.to_owned()),
+            ("backslash", r"C:\wg".to_owned()),
+            ("newline", "a\nb".to_owned()),
+        ];
+        sample(&mut out, "metric", &labels, 7);
+        let expected =
+            r#"metric{plain="mynet",quoted="a \"b\" c",backslash="C:\\wg",newline="a\nb"} 7"#;
+        assert_eq!(out, format!("{expected}\n"));
+
+        let mut out = String::new();
+        sample(&mut out, "metric", &[], 0);
+        assert_eq!(out, "metric 0\n");
+    }
+}
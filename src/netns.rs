@@ -37,23 +37,16 @@ impl NetworkNamespaceOptions {
         }
     }
 
-    pub(crate) fn from_env() -> Self {
-        use std::env;
-        match env::var_os("NETNS") {
-            Some(path) => {
-                if path == "auto" {
-                    if let Some(path) = env::var_os("NETNS_AUTO_PATH") {
-                        NetworkNamespaceOptions::auto_with_path(path)
-                    } else {
-                        NetworkNamespaceOptions::auto()
-                    }
-                } else if path == "inherit" {
-                    NetworkNamespaceOptions::inherit()
-                } else {
-                    NetworkNamespaceOptions::from_path(path)
-                }
-            }
-            None => Default::default(),
+    /// Options for a `NETNS` mode: `auto`, `inherit`, or the path of a
+    /// namespace. `auto_path` replaces the default path in `auto` mode.
+    pub(crate) fn from_mode(mode: Option<&str>, auto_path: Option<PathBuf>) -> Self {
+        match mode {
+            None | Some("auto") => match auto_path {
+                Some(path) => NetworkNamespaceOptions::auto_with_path(path),
+                None => NetworkNamespaceOptions::auto(),
+            },
+            Some("inherit") => NetworkNamespaceOptions::inherit(),
+            Some(path) => NetworkNamespaceOptions::from_path(path),
         }
     }
 
@@ -111,3 +104,28 @@ pub(crate) fn enter_net_namespace(options: &NetworkNamespaceOptions) -> Result<(
         Error
     })
 }
+
+/// Run `f` on a new thread that has joined the network namespace at `path`.
+///
+/// The calling thread is left in its own namespace. Sockets created by `f`
+/// stay bound to the target namespace after it returns, so this can be used
+/// to open netlink connections into a container sandbox.
+pub(crate) fn run_in_namespace<F, T>(path: &Path, f: F) -> std::io::Result<T>
+where
+    F: FnOnce() -> std::io::Result<T> + Send,
+    T: Send,
+{
+    use rustix::thread::{move_into_link_name_space, LinkNameSpaceType};
+    use std::os::fd::AsFd;
+
+    let netns_file = std::fs::File::open(path)?;
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                move_into_link_name_space(netns_file.as_fd(), Some(LinkNameSpaceType::Network))?;
+                f()
+            })
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("namespace thread panicked")))
+    })
+}
@@ -0,0 +1,469 @@
+//! Minimal nftables client over netlink.
+//!
+//! Only the handful of messages needed by the kill switch are supported.
+//! Messages are collected in a batch and committed as a single transaction.
+
+use rtnetlink::proto::sys::{protocols::NETLINK_NETFILTER, Socket, SocketAddr};
+
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLA_F_NESTED: u16 = 0x8000;
+
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFNETLINK_V0: u8 = 0;
+
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFT_MSG_NEWSET: u16 = 9;
+const NFT_MSG_NEWSETELEM: u16 = 12;
+const NFT_MSG_DELSETELEM: u16 = 14;
+
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_LOOKUP_SET: u16 = 1;
+const NFTA_LOOKUP_SREG: u16 = 2;
+const NFTA_LOOKUP_SET_ID: u16 = 4;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFTA_SET_TABLE: u16 = 1;
+const NFTA_SET_NAME: u16 = 2;
+const NFTA_SET_KEY_TYPE: u16 = 4;
+const NFTA_SET_KEY_LEN: u16 = 5;
+const NFTA_SET_ID: u16 = 10;
+const NFTA_SET_ELEM_KEY: u16 = 1;
+const NFTA_SET_ELEM_LIST_TABLE: u16 = 1;
+const NFTA_SET_ELEM_LIST_SET: u16 = 2;
+const NFTA_SET_ELEM_LIST_ELEMENTS: u16 = 3;
+const NFTA_SET_ELEM_LIST_SET_ID: u16 = 4;
+
+const NFPROTO_INET: u8 = 1;
+const NF_INET_LOCAL_OUT: u32 = 3;
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+const NFT_META_OIF: u32 = 5;
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+/// nft userspace data type for interface indexes, only used for display.
+const TYPE_IFINDEX: u32 = 20;
+
+/// All sets created in a batch get this id, there is never more than one.
+const SET_ID: u32 = 1;
+
+/// A transaction on the `inet` family.
+pub(crate) struct Batch {
+    buf: Vec<u8>,
+    seq: u32,
+    requests: usize,
+}
+
+impl Batch {
+    pub(crate) fn new() -> Self {
+        let mut batch = Self {
+            buf: Vec::new(),
+            seq: 0,
+            requests: 0,
+        };
+        let start =
+            batch.begin_message(NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST, 0, NFNL_SUBSYS_NFTABLES);
+        batch.end_message(start);
+        batch
+    }
+
+    /// Create a table. The transaction fails with `EEXIST` if it exists.
+    pub(crate) fn new_table(&mut self, table: &str) -> &mut Self {
+        let start = self.request(NFT_MSG_NEWTABLE, NLM_F_CREATE | NLM_F_EXCL);
+        put_str(&mut self.buf, NFTA_TABLE_NAME, table);
+        self.end_message(start);
+        self
+    }
+
+    pub(crate) fn del_table(&mut self, table: &str) -> &mut Self {
+        let start = self.request(NFT_MSG_DELTABLE, 0);
+        put_str(&mut self.buf, NFTA_TABLE_NAME, table);
+        self.end_message(start);
+        self
+    }
+
+    /// Create a set of interface indexes.
+    pub(crate) fn new_ifindex_set(&mut self, table: &str, set: &str) -> &mut Self {
+        let start = self.request(NFT_MSG_NEWSET, NLM_F_CREATE);
+        put_str(&mut self.buf, NFTA_SET_TABLE, table);
+        put_str(&mut self.buf, NFTA_SET_NAME, set);
+        put_be32(&mut self.buf, NFTA_SET_KEY_TYPE, TYPE_IFINDEX);
+        put_be32(&mut self.buf, NFTA_SET_KEY_LEN, 4);
+        put_be32(&mut self.buf, NFTA_SET_ID, SET_ID);
+        self.end_message(start);
+        self
+    }
+
+    /// Create a filter chain on the output hook that drops anything that is
+    /// not accepted by one of its rules.
+    pub(crate) fn new_output_chain(&mut self, table: &str, chain: &str) -> &mut Self {
+        let start = self.request(NFT_MSG_NEWCHAIN, NLM_F_CREATE);
+        put_str(&mut self.buf, NFTA_CHAIN_TABLE, table);
+        put_str(&mut self.buf, NFTA_CHAIN_NAME, chain);
+        nested(&mut self.buf, NFTA_CHAIN_HOOK, |buf| {
+            put_be32(buf, NFTA_HOOK_HOOKNUM, NF_INET_LOCAL_OUT);
+            put_be32(buf, NFTA_HOOK_PRIORITY, 0);
+        });
+        put_be32(&mut self.buf, NFTA_CHAIN_POLICY, NF_DROP);
+        put_str(&mut self.buf, NFTA_CHAIN_TYPE, "filter");
+        self.end_message(start);
+        self
+    }
+
+    /// Add `meta oif @set accept` to a chain.
+    pub(crate) fn accept_oif_in_set(&mut self, table: &str, chain: &str, set: &str) -> &mut Self {
+        let start = self.request(NFT_MSG_NEWRULE, NLM_F_CREATE);
+        put_str(&mut self.buf, NFTA_RULE_TABLE, table);
+        put_str(&mut self.buf, NFTA_RULE_CHAIN, chain);
+        nested(&mut self.buf, NFTA_RULE_EXPRESSIONS, |buf| {
+            expression(buf, "meta", |buf| {
+                put_be32(buf, NFTA_META_DREG, NFT_REG_1);
+                put_be32(buf, NFTA_META_KEY, NFT_META_OIF);
+            });
+            expression(buf, "lookup", |buf| {
+                put_str(buf, NFTA_LOOKUP_SET, set);
+                put_be32(buf, NFTA_LOOKUP_SREG, NFT_REG_1);
+                put_be32(buf, NFTA_LOOKUP_SET_ID, SET_ID);
+            });
+            expression(buf, "immediate", |buf| {
+                put_be32(buf, NFTA_IMMEDIATE_DREG, NFT_REG_VERDICT);
+                nested(buf, NFTA_IMMEDIATE_DATA, |buf| {
+                    nested(buf, NFTA_DATA_VERDICT, |buf| {
+                        put_be32(buf, NFTA_VERDICT_CODE, NF_ACCEPT);
+                    });
+                });
+            });
+        });
+        self.end_message(start);
+        self
+    }
+
+    pub(crate) fn add_ifindex(&mut self, table: &str, set: &str, index: u32) -> &mut Self {
+        self.set_element(NFT_MSG_NEWSETELEM, NLM_F_CREATE, table, set, index)
+    }
+
+    pub(crate) fn del_ifindex(&mut self, table: &str, set: &str, index: u32) -> &mut Self {
+        self.set_element(NFT_MSG_DELSETELEM, 0, table, set, index)
+    }
+
+    fn set_element(
+        &mut self,
+        msg_type: u16,
+        flags: u16,
+        table: &str,
+        set: &str,
+        index: u32,
+    ) -> &mut Self {
+        let start = self.request(msg_type, flags);
+        put_str(&mut self.buf, NFTA_SET_ELEM_LIST_TABLE, table);
+        put_str(&mut self.buf, NFTA_SET_ELEM_LIST_SET, set);
+        put_be32(&mut self.buf, NFTA_SET_ELEM_LIST_SET_ID, SET_ID);
+        nested(&mut self.buf, NFTA_SET_ELEM_LIST_ELEMENTS, |buf| {
+            nested(buf, NFTA_LIST_ELEM, |buf| {
+                nested(buf, NFTA_SET_ELEM_KEY, |buf| {
+                    // the meta expression loads the index in host byte order
+                    put(buf, NFTA_DATA_VALUE, &index.to_ne_bytes());
+                });
+            });
+        });
+        self.end_message(start);
+        self
+    }
+
+    /// Commit the transaction, and wait for the kernel to acknowledge it.
+    pub(crate) fn send(mut self) -> std::io::Result<()> {
+        let start = self.begin_message(NFNL_MSG_BATCH_END, NLM_F_REQUEST, 0, NFNL_SUBSYS_NFTABLES);
+        self.end_message(start);
+
+        let mut socket = Socket::new(NETLINK_NETFILTER)?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+        socket.send(&self.buf, 0)?;
+
+        let mut acked = 0;
+        let mut buf = Vec::with_capacity(16 * 1024);
+        while acked < self.requests {
+            buf.clear();
+            let len = socket.recv(&mut buf, 0)?;
+            let mut messages = &buf[..len];
+            while messages.len() >= 16 {
+                let msg_len = u32::from_ne_bytes(messages[0..4].try_into().unwrap()) as usize;
+                let msg_type = u16::from_ne_bytes(messages[4..6].try_into().unwrap());
+                if msg_len < 16 || msg_len > messages.len() {
+                    break;
+                }
+                if msg_type == NLMSG_ERROR && msg_len >= 20 {
+                    let code = i32::from_ne_bytes(messages[16..20].try_into().unwrap());
+                    if code != 0 {
+                        return Err(std::io::Error::from_raw_os_error(-code));
+                    }
+                    acked += 1;
+                }
+                messages = &messages[align(msg_len).min(messages.len())..];
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, msg_type: u16, flags: u16) -> usize {
+        self.requests += 1;
+        self.begin_message(
+            (NFNL_SUBSYS_NFTABLES << 8) | msg_type,
+            NLM_F_REQUEST | NLM_F_ACK | flags,
+            NFPROTO_INET,
+            0,
+        )
+    }
+
+    fn begin_message(&mut self, msg_type: u16, flags: u16, family: u8, res_id: u16) -> usize {
+        let start = self.buf.len();
+        self.seq += 1;
+        // struct nlmsghdr, the length is filled in by end_message()
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        self.buf.extend_from_slice(&msg_type.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&self.seq.to_ne_bytes());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        // struct nfgenmsg
+        self.buf.push(family);
+        self.buf.push(NFNETLINK_V0);
+        self.buf.extend_from_slice(&res_id.to_be_bytes());
+        start
+    }
+
+    fn end_message(&mut self, start: usize) {
+        let len = (self.buf.len() - start) as u32;
+        self.buf[start..start + 4].copy_from_slice(&len.to_ne_bytes());
+    }
+}
+
+const fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn put(buf: &mut Vec<u8>, attr_type: u16, data: &[u8]) {
+    let len = (4 + data.len()) as u16;
+    buf.extend_from_slice(&len.to_ne_bytes());
+    buf.extend_from_slice(&attr_type.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(align(buf.len()), 0);
+}
+
+fn put_str(buf: &mut Vec<u8>, attr_type: u16, value: &str) {
+    let mut data = Vec::with_capacity(value.len() + 1);
+    data.extend_from_slice(value.as_bytes());
+    data.push(0);
+    put(buf, attr_type, &data);
+}
+
+fn put_be32(buf: &mut Vec<u8>, attr_type: u16, value: u32) {
+    put(buf, attr_type, &value.to_be_bytes());
+}
+
+fn nested(buf: &mut Vec<u8>, attr_type: u16, f: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend_from_slice(&0u16.to_ne_bytes());
+    buf.extend_from_slice(&(attr_type | NLA_F_NESTED).to_ne_bytes());
+    f(buf);
+    let len = (buf.len() - start) as u16;
+    buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+}
+
+fn expression(buf: &mut Vec<u8>, name: &str, f: impl FnOnce(&mut Vec<u8>)) {
+    nested(buf, NFTA_LIST_ELEM, |buf| {
+        put_str(buf, NFTA_EXPR_NAME, name);
+        nested(buf, NFTA_EXPR_DATA, f);
+    });
+}
+
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+
+    /// The messages of a batch, after the batch begin message.
+    fn requests(batch: &Batch) -> &[u8] {
+        &batch.buf[20..]
+    }
+
+    #[test]
+    fn test_batch_begin() {
+        let batch = Batch::new();
+        #[rustfmt::skip]
+        let expected = [
+            // nlmsghdr: length, NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST, seq 1, pid 0
+            20, 0, 0, 0,  0x10, 0,  1, 0,  1, 0, 0, 0,  0, 0, 0, 0,
+            // nfgenmsg: AF_UNSPEC, version 0, NFNL_SUBSYS_NFTABLES (big endian)
+            0, 0, 0, 10,
+        ];
+        assert_eq!(batch.buf, expected);
+        assert_eq!(batch.requests, 0);
+    }
+
+    #[test]
+    fn test_new_table() {
+        let mut batch = Batch::new();
+        batch.new_table("t");
+        #[rustfmt::skip]
+        let expected = [
+            // NFT_MSG_NEWTABLE, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL, seq 2
+            28, 0, 0, 0,  0x00, 0x0a,  0x05, 0x06,  2, 0, 0, 0,  0, 0, 0, 0,
+            // NFPROTO_INET
+            1, 0, 0, 0,
+            // NFTA_TABLE_NAME "t", padded
+            6, 0, 1, 0,  b't', 0, 0, 0,
+        ];
+        assert_eq!(requests(&batch), expected);
+        assert_eq!(batch.requests, 1);
+    }
+
+    #[test]
+    fn test_new_ifindex_set() {
+        let mut batch = Batch::new();
+        batch.new_ifindex_set("t", "s");
+        #[rustfmt::skip]
+        let expected = [
+            // NFT_MSG_NEWSET, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE
+            60, 0, 0, 0,  0x09, 0x0a,  0x05, 0x04,  2, 0, 0, 0,  0, 0, 0, 0,
+            1, 0, 0, 0,
+            // NFTA_SET_TABLE, NFTA_SET_NAME
+            6, 0, 1, 0,  b't', 0, 0, 0,
+            6, 0, 2, 0,  b's', 0, 0, 0,
+            // NFTA_SET_KEY_TYPE: ifindex, NFTA_SET_KEY_LEN: 4, NFTA_SET_ID: 1
+            8, 0, 4, 0,  0, 0, 0, 20,
+            8, 0, 5, 0,  0, 0, 0, 4,
+            8, 0, 10, 0,  0, 0, 0, 1,
+        ];
+        assert_eq!(requests(&batch), expected);
+    }
+
+    #[test]
+    fn test_new_output_chain() {
+        let mut batch = Batch::new();
+        batch.new_output_chain("t", "c");
+        #[rustfmt::skip]
+        let expected = [
+            // NFT_MSG_NEWCHAIN, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE
+            76, 0, 0, 0,  0x03, 0x0a,  0x05, 0x04,  2, 0, 0, 0,  0, 0, 0, 0,
+            1, 0, 0, 0,
+            // NFTA_CHAIN_TABLE, NFTA_CHAIN_NAME
+            6, 0, 1, 0,  b't', 0, 0, 0,
+            6, 0, 3, 0,  b'c', 0, 0, 0,
+            // NFTA_CHAIN_HOOK: NF_INET_LOCAL_OUT, priority 0
+            20, 0, 4, 0x80,
+                8, 0, 1, 0,  0, 0, 0, 3,
+                8, 0, 2, 0,  0, 0, 0, 0,
+            // NFTA_CHAIN_POLICY: NF_DROP
+            8, 0, 5, 0,  0, 0, 0, 0,
+            // NFTA_CHAIN_TYPE "filter"
+            11, 0, 7, 0,  b'f', b'i', b'l', b't', b'e', b'r', 0, 0,
+        ];
+        assert_eq!(requests(&batch), expected);
+    }
+
+    #[test]
+    fn test_accept_oif_in_set() {
+        let mut batch = Batch::new();
+        batch.accept_oif_in_set("t", "c", "s");
+        #[rustfmt::skip]
+        let expected = [
+            // NFT_MSG_NEWRULE, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE
+            168, 0, 0, 0,  0x06, 0x0a,  0x05, 0x04,  2, 0, 0, 0,  0, 0, 0, 0,
+            1, 0, 0, 0,
+            // NFTA_RULE_TABLE, NFTA_RULE_CHAIN
+            6, 0, 1, 0,  b't', 0, 0, 0,
+            6, 0, 2, 0,  b'c', 0, 0, 0,
+            // NFTA_RULE_EXPRESSIONS
+            132, 0, 4, 0x80,
+                // meta load oif => reg 1
+                36, 0, 1, 0x80,
+                    9, 0, 1, 0,  b'm', b'e', b't', b'a',  0, 0, 0, 0,
+                    20, 0, 2, 0x80,
+                        8, 0, 1, 0,  0, 0, 0, 1,
+                        8, 0, 2, 0,  0, 0, 0, 5,
+                // lookup reg 1 set s
+                44, 0, 1, 0x80,
+                    11, 0, 1, 0,  b'l', b'o', b'o', b'k',  b'u', b'p', 0, 0,
+                    28, 0, 2, 0x80,
+                        6, 0, 1, 0,  b's', 0, 0, 0,
+                        8, 0, 2, 0,  0, 0, 0, 1,
+                        8, 0, 4, 0,  0, 0, 0, 1,
+                // immediate reg 0 accept
+                48, 0, 1, 0x80,
+                    14, 0, 1, 0,  b'i', b'm', b'm', b'e',  b'd', b'i', b'a', b't',  b'e', 0, 0, 0,
+                    28, 0, 2, 0x80,
+                        8, 0, 1, 0,  0, 0, 0, 0,
+                        16, 0, 2, 0x80,
+                            12, 0, 2, 0x80,
+                                8, 0, 1, 0,  0, 0, 0, 1,
+        ];
+        assert_eq!(requests(&batch), expected);
+    }
+
+    #[test]
+    fn test_set_elements() {
+        #[rustfmt::skip]
+        let expected = |msg_type: u8, flags: u8| [
+            64, 0, 0, 0,  msg_type, 0x0a,  0x05, flags,  2, 0, 0, 0,  0, 0, 0, 0,
+            1, 0, 0, 0,
+            // NFTA_SET_ELEM_LIST_TABLE, NFTA_SET_ELEM_LIST_SET, NFTA_SET_ELEM_LIST_SET_ID
+            6, 0, 1, 0,  b't', 0, 0, 0,
+            6, 0, 2, 0,  b's', 0, 0, 0,
+            8, 0, 4, 0,  0, 0, 0, 1,
+            // NFTA_SET_ELEM_LIST_ELEMENTS
+            20, 0, 3, 0x80,
+                16, 0, 1, 0x80,
+                    // NFTA_SET_ELEM_KEY, the index in host byte order
+                    12, 0, 1, 0x80,
+                        8, 0, 1, 0,  3, 0, 0, 0,
+        ];
+
+        let mut batch = Batch::new();
+        batch.add_ifindex("t", "s", 3);
+        // NFT_MSG_NEWSETELEM, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE
+        assert_eq!(requests(&batch), expected(0x0c, 0x04));
+
+        let mut batch = Batch::new();
+        batch.del_ifindex("t", "s", 3);
+        // NFT_MSG_DELSETELEM, NLM_F_REQUEST | NLM_F_ACK
+        assert_eq!(requests(&batch), expected(0x0e, 0x00));
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let mut batch = Batch::new();
+        batch.new_table("t").del_table("t");
+        let second = &requests(&batch)[28..];
+        // NFT_MSG_DELTABLE, NLM_F_REQUEST | NLM_F_ACK, seq 3
+        assert_eq!(
+            second[..16],
+            [28, 0, 0, 0, 0x02, 0x0a, 0x05, 0x00, 3, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(batch.requests, 2);
+    }
+}
@@ -0,0 +1,339 @@
+//! Configuration applied from inside a container network namespace.
+//!
+//! Docker moves the interface into the sandbox only after the Join response,
+//! so anything that Docker can't do for us has to wait for the link to show
+//! up in the sandbox namespace.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures_util::stream::TryStreamExt;
+use rtnetlink::packet_route::link::{InfoKind, LinkAttribute, LinkInfo, LinkMessage};
+use thiserror::Error;
+
+use crate::netns;
+use crate::nft;
+use crate::wg::CidrAddress;
+
+const LINK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const LINK_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+const PROCESS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const PROCESS_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+const KILLSWITCH_TABLE: &str = "wireguard_killswitch";
+const KILLSWITCH_CHAIN: &str = "output";
+const KILLSWITCH_SET: &str = "allowed_oif";
+/// The loopback interface always has this index in a network namespace.
+const LOOPBACK_IFINDEX: u32 = 1;
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("rtnetlink error: {0}")]
+    Netlink(#[from] rtnetlink::Error),
+    #[error("timed out waiting for the {0}")]
+    Timeout(&'static str),
+}
+
+pub(crate) struct Sandbox {
+    rt: rtnetlink::Handle,
+}
+
+impl Sandbox {
+    /// Open a netlink connection into the network namespace at `path`.
+    pub(crate) async fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path: PathBuf = path.as_ref().to_owned();
+        let runtime = tokio::runtime::Handle::current();
+        let (connection, rt, _) = tokio::task::spawn_blocking(move || {
+            netns::run_in_namespace(&path, || {
+                // the netlink socket registers with the reactor on creation
+                let _guard = runtime.enter();
+                rtnetlink::new_connection()
+            })
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        tokio::spawn(connection);
+        Ok(Self { rt })
+    }
+
+    /// Wait until Docker has moved our WireGuard link into the sandbox, and
+    /// return its index in the sandbox.
+    ///
+    /// Docker renames the link, so we look it up by the index it had when it
+    /// was created, falling back to the original name.
+    pub(crate) async fn wait_for_link(&self, index: u32, name: &str) -> Result<u32, Error> {
+        let deadline = tokio::time::Instant::now() + LINK_WAIT_TIMEOUT;
+        loop {
+            let mut links = self.rt.link().get().execute();
+            let mut by_name = None;
+            while let Some(link) = links.try_next().await? {
+                if !is_wireguard(&link) {
+                    continue;
+                }
+                if link.header.index == index {
+                    return Ok(index);
+                }
+                if link_name(&link) == Some(name) {
+                    by_name = Some(link.header.index);
+                }
+            }
+            if let Some(index) = by_name {
+                return Ok(index);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(Error::Timeout("interface to appear in the sandbox"));
+            }
+            tokio::time::sleep(LINK_POLL_INTERVAL).await;
+        }
+    }
+
+    pub(crate) async fn add_addresses<'a>(
+        &self,
+        index: u32,
+        addresses: impl IntoIterator<Item = &'a CidrAddress>,
+    ) -> Result<(), Error> {
+        for address in addresses {
+            log::debug!(address:display, index; "Adding address in sandbox");
+            self.rt
+                .address()
+                .add(index, *address.ip(), address.cidr())
+                .execute()
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Kill switches installed in container sandboxes, by endpoint.
+///
+/// The sandbox gets a single nftables table with an output chain that drops
+/// everything, except for traffic going out of the interfaces in a set. The
+/// set starts with just the loopback interface, and each endpoint adds its
+/// WireGuard interface once it shows up in the sandbox.
+///
+/// The rules outlive the plugin, so after a restart the kill switches are
+/// [restored](Self::restore) from the endpoint records.
+#[derive(Default)]
+pub(crate) struct KillSwitches {
+    endpoints: Mutex<HashMap<String, KillSwitch>>,
+}
+
+struct KillSwitch {
+    sandbox_key: PathBuf,
+    index: Option<u32>,
+}
+
+impl KillSwitches {
+    /// Block all egress traffic from the sandbox, other than loopback.
+    pub(crate) async fn install(
+        &self,
+        endpoint_id: String,
+        sandbox_key: PathBuf,
+    ) -> Result<(), Error> {
+        const EEXIST: i32 = rustix::io::Errno::EXIST.raw_os_error();
+        let result = nft_in_sandbox(&sandbox_key, |batch| {
+            batch
+                .new_table(KILLSWITCH_TABLE)
+                .new_ifindex_set(KILLSWITCH_TABLE, KILLSWITCH_SET)
+                .new_output_chain(KILLSWITCH_TABLE, KILLSWITCH_CHAIN)
+                .accept_oif_in_set(KILLSWITCH_TABLE, KILLSWITCH_CHAIN, KILLSWITCH_SET)
+                .add_ifindex(KILLSWITCH_TABLE, KILLSWITCH_SET, LOOPBACK_IFINDEX);
+        })
+        .await;
+        match result {
+            Ok(()) => {}
+            // another endpoint in the same sandbox already installed it
+            Err(Error::Io(err)) if err.raw_os_error() == Some(EEXIST) => {}
+            Err(err) => return Err(err),
+        }
+        log::debug!(endpoint_id = endpoint_id.as_str(); "Kill switch installed");
+        self.endpoints.lock().unwrap().insert(
+            endpoint_id,
+            KillSwitch {
+                sandbox_key,
+                index: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Take over a kill switch installed by a previous run of the plugin.
+    pub(crate) fn restore(&self, endpoint_id: String, sandbox_key: PathBuf, index: Option<u32>) {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .insert(endpoint_id, KillSwitch { sandbox_key, index });
+    }
+
+    /// Allow traffic through the WireGuard interface of an endpoint, once we
+    /// know its index in the sandbox.
+    pub(crate) async fn allow(&self, endpoint_id: &str, index: u32) -> Result<(), Error> {
+        let Some(sandbox_key) = self
+            .endpoints
+            .lock()
+            .unwrap()
+            .get(endpoint_id)
+            .map(|ks| ks.sandbox_key.clone())
+        else {
+            return Ok(());
+        };
+        nft_in_sandbox(&sandbox_key, |batch| {
+            batch.add_ifindex(KILLSWITCH_TABLE, KILLSWITCH_SET, index);
+        })
+        .await?;
+        if let Some(ks) = self.endpoints.lock().unwrap().get_mut(endpoint_id) {
+            ks.index = Some(index);
+        }
+        Ok(())
+    }
+
+    /// Remove the kill switch of an endpoint. The table is deleted when no
+    /// other endpoint in the same sandbox needs it.
+    pub(crate) async fn remove(&self, endpoint_id: &str) -> Result<(), Error> {
+        let (killswitch, shared) = {
+            let mut endpoints = self.endpoints.lock().unwrap();
+            let Some(killswitch) = endpoints.remove(endpoint_id) else {
+                return Ok(());
+            };
+            let shared = endpoints
+                .values()
+                .any(|other| other.sandbox_key == killswitch.sandbox_key);
+            (killswitch, shared)
+        };
+        let result = match (shared, killswitch.index) {
+            (false, _) => {
+                nft_in_sandbox(&killswitch.sandbox_key, |batch| {
+                    batch.del_table(KILLSWITCH_TABLE);
+                })
+                .await
+            }
+            (true, Some(index)) => {
+                nft_in_sandbox(&killswitch.sandbox_key, |batch| {
+                    batch.del_ifindex(KILLSWITCH_TABLE, KILLSWITCH_SET, index);
+                })
+                .await
+            }
+            (true, None) => Ok(()),
+        };
+        match result {
+            // the sandbox or the table is already gone
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+async fn nft_in_sandbox(
+    sandbox_key: &Path,
+    f: impl FnOnce(&mut nft::Batch) + Send + 'static,
+) -> Result<(), Error> {
+    let path = sandbox_key.to_owned();
+    tokio::task::spawn_blocking(move || {
+        netns::run_in_namespace(&path, || {
+            let mut batch = nft::Batch::new();
+            f(&mut batch);
+            batch.send()
+        })
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+    Ok(())
+}
+
+/// Render a resolv.conf using the given servers and search domains.
+pub(crate) fn resolv_conf(servers: &[IpAddr], search: &[String]) -> String {
+    let mut contents = String::from("# Generated by wireguard-docker-plugin\n");
+    for server in servers {
+        writeln!(contents, "nameserver {server}").unwrap();
+    }
+    if !search.is_empty() {
+        writeln!(contents, "search {}", search.join(" ")).unwrap();
+    }
+    contents
+}
+
+/// Replace the resolv.conf of the container that owns the sandbox.
+///
+/// There is no container id in the Join request, so we look for a process
+/// that lives in the sandbox network namespace, and wait until Docker has
+/// bind-mounted its resolv.conf. The file is then written through
+/// `/proc/<pid>/root`, which requires the plugin to share the host PID
+/// namespace.
+pub(crate) async fn write_resolv_conf(
+    sandbox_key: impl AsRef<Path>,
+    contents: String,
+) -> Result<(), Error> {
+    let netns = std::fs::metadata(sandbox_key.as_ref())?;
+    let netns = (netns.dev(), netns.ino());
+    let deadline = tokio::time::Instant::now() + PROCESS_WAIT_TIMEOUT;
+    loop {
+        let pid = tokio::task::spawn_blocking(move || find_container_process(netns))
+            .await
+            .map_err(std::io::Error::other)??;
+        if let Some(pid) = pid {
+            let path = PathBuf::from(format!("/proc/{pid}/root/etc/resolv.conf"));
+            log::debug!(pid; "Writing container resolv.conf");
+            tokio::fs::write(path, contents).await?;
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(Error::Timeout("container process to start"));
+        }
+        tokio::time::sleep(PROCESS_POLL_INTERVAL).await;
+    }
+}
+
+/// Find a process in the given network namespace (device and inode of the
+/// nsfs file) whose /etc/resolv.conf has already been mounted.
+fn find_container_process(netns: (u64, u64)) -> std::io::Result<Option<u32>> {
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        // processes come and go, so errors here are not fatal
+        let Ok(ns) = std::fs::metadata(entry.path().join("ns/net")) else {
+            continue;
+        };
+        if (ns.dev(), ns.ino()) != netns {
+            continue;
+        }
+        let Ok(mountinfo) = std::fs::read_to_string(entry.path().join("mountinfo")) else {
+            continue;
+        };
+        let mounted = mountinfo
+            .lines()
+            .any(|line| line.split(' ').nth(4) == Some("/etc/resolv.conf"));
+        if mounted {
+            return Ok(Some(pid));
+        }
+    }
+    Ok(None)
+}
+
+fn is_wireguard(link: &LinkMessage) -> bool {
+    link.attributes.iter().any(|attr| match attr {
+        LinkAttribute::LinkInfo(infos) => infos
+            .iter()
+            .any(|info| matches!(info, LinkInfo::Kind(InfoKind::Wireguard))),
+        _ => false,
+    })
+}
+
+fn link_name(link: &LinkMessage) -> Option<&str> {
+    link.attributes.iter().find_map(|attr| match attr {
+        LinkAttribute::IfName(name) => Some(name.as_str()),
+        _ => None,
+    })
+}
@@ -0,0 +1,416 @@
+//! Command-line options, and the optional daemon configuration file.
+//!
+//! Each setting is taken from the command line, then from its environment
+//! variable, then from the configuration file, and falls back to a default.
+//! Environment variables are prefixed with `WG_PLUGIN_`, so that they don't
+//! collide with the ones of other programs; the unprefixed variables of older
+//! versions are still read, after the prefixed ones.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::logging::{LogFormat, LogOutput, LogSettings};
+use crate::netns::NetworkNamespaceOptions;
+use crate::wg::WatchdogSettings;
+
+const DEFAULT_PLUGIN_NAME: &str = "wireguard";
+const DEFAULT_DB_DIR: &str = "wireguard_db";
+const DEFAULT_CONF_DIR: &str = "wireguard_conf";
+const DEFAULT_INTERFACE_PREFIX: &str = "wgdkr";
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interface names are the prefix and 8 characters of the endpoint id, and
+/// Linux allows at most 15 characters.
+const MAX_INTERFACE_PREFIX_LEN: usize = 7;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Docker network plugin for WireGuard")]
+pub(crate) struct Cli {
+    /// Daemon configuration file, in TOML
+    #[arg(long, short, env = "WG_PLUGIN_CONFIG_FILE", global = true)]
+    pub(crate) config: Option<PathBuf>,
+    #[command(flatten)]
+    pub(crate) options: Options,
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Check configuration files for mistakes, without creating interfaces
+    Check {
+        /// Files or directories to check. By default, all the files of the
+        /// configuration directory.
+        paths: Vec<PathBuf>,
+    },
+}
+
+/// Settings that can be given on the command line, as environment variables
+/// or in the configuration file. Options that are not given are `None`.
+#[derive(Args, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Options {
+    /// Name of the plugin, used as the driver name in Docker [default: wireguard]
+    #[arg(long, env = "WG_PLUGIN_NAME")]
+    plugin_name: Option<String>,
+    /// Path of the plugin socket [default: /run/docker/plugins/<plugin name>.sock]
+    #[arg(long, env = "WG_PLUGIN_SOCKET")]
+    socket: Option<PathBuf>,
+    /// Directory of the plugin state [default: wireguard_db]
+    #[arg(long, env = "WG_PLUGIN_DB_DIR")]
+    db_dir: Option<PathBuf>,
+    /// Directory of the WireGuard configuration files [default: wireguard_conf]
+    #[arg(long, env = "WG_PLUGIN_CONF_DIR", global = true)]
+    conf_dir: Option<PathBuf>,
+    /// Log level [default: warn, or debug in debug builds]
+    #[arg(long, env = "WG_PLUGIN_LOG_LEVEL")]
+    log_level: Option<LogLevel>,
+    /// Log one level more than the default
+    #[arg(
+        long,
+        env = "WG_PLUGIN_DEBUG",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = clap::builder::BoolishValueParser::new(),
+    )]
+    debug: Option<bool>,
+    /// Format of log lines [default: text]
+    #[arg(long, env = "WG_PLUGIN_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Log file, or `stderr` or `stdout` [default: stderr]
+    #[arg(long, env = "WG_PLUGIN_LOG_FILE")]
+    log_file: Option<PathBuf>,
+    /// Network namespace to run in: `auto`, `inherit` or a path [default: auto]
+    #[arg(long, env = "WG_PLUGIN_NETNS")]
+    netns: Option<String>,
+    /// Network namespace path used in `auto` mode, if it exists [default: /parent-netns]
+    #[arg(long, env = "WG_PLUGIN_NETNS_AUTO_PATH")]
+    netns_auto_path: Option<PathBuf>,
+    /// Prefix of the names of the WireGuard interfaces [default: wgdkr]
+    #[arg(long, env = "WG_PLUGIN_INTERFACE_PREFIX")]
+    interface_prefix: Option<String>,
+    /// Serve metrics on this TCP address, or unix socket path
+    #[arg(long, env = "WG_PLUGIN_METRICS_LISTEN")]
+    metrics_listen: Option<String>,
+    /// Seconds without a handshake before a peer is stale [default: three
+    /// times the persistent keepalive, or 180]
+    #[arg(long, env = "WG_PLUGIN_HANDSHAKE_TIMEOUT")]
+    handshake_timeout: Option<u64>,
+    /// Set the endpoints of stale peers again, resolving hostnames
+    #[arg(
+        long,
+        env = "WG_PLUGIN_HANDSHAKE_RECOVER",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = clap::builder::BoolishValueParser::new(),
+    )]
+    handshake_recover: Option<bool>,
+    /// Seconds to wait for requests in flight when shutting down [default: 30]
+    #[arg(long, env = "WG_PLUGIN_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<u64>,
+}
+
+impl Options {
+    fn from_file(path: &Path) -> Result<Self, Error> {
+        let text =
+            std::fs::read_to_string(path).map_err(|err| Error::Read(path.to_owned(), err))?;
+        toml::from_str(&text).map_err(|err| Error::Parse(path.to_owned(), err))
+    }
+
+    /// Options from the environment variables of older versions, looked up
+    /// with `var`. As before, `DEBUG` only counts when it is `1`, and other
+    /// values are ignored.
+    fn from_legacy_env(var: impl Fn(&'static str) -> Option<OsString>) -> Self {
+        Options {
+            debug: var("DEBUG")
+                .is_some_and(|value| value.to_str().map(str::trim) == Some("1"))
+                .then_some(true),
+            log_file: var("LOGFILE").map(PathBuf::from),
+            netns: var("NETNS").and_then(|value| value.into_string().ok()),
+            netns_auto_path: var("NETNS_AUTO_PATH").map(PathBuf::from),
+            ..Default::default()
+        }
+    }
+
+    /// Take each option that is not set from `other`.
+    fn or(self, other: Options) -> Options {
+        Options {
+            plugin_name: self.plugin_name.or(other.plugin_name),
+            socket: self.socket.or(other.socket),
+            db_dir: self.db_dir.or(other.db_dir),
+            conf_dir: self.conf_dir.or(other.conf_dir),
+            log_level: self.log_level.or(other.log_level),
+            debug: self.debug.or(other.debug),
+            log_format: self.log_format.or(other.log_format),
+            log_file: self.log_file.or(other.log_file),
+            netns: self.netns.or(other.netns),
+            netns_auto_path: self.netns_auto_path.or(other.netns_auto_path),
+            interface_prefix: self.interface_prefix.or(other.interface_prefix),
+            metrics_listen: self.metrics_listen.or(other.metrics_listen),
+            handshake_timeout: self.handshake_timeout.or(other.handshake_timeout),
+            handshake_recover: self.handshake_recover.or(other.handshake_recover),
+            shutdown_timeout: self.shutdown_timeout.or(other.shutdown_timeout),
+        }
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+fn more_verbose(level: log::LevelFilter) -> log::LevelFilter {
+    match level {
+        log::LevelFilter::Off => log::LevelFilter::Error,
+        log::LevelFilter::Error => log::LevelFilter::Warn,
+        log::LevelFilter::Warn => log::LevelFilter::Info,
+        log::LevelFilter::Info => log::LevelFilter::Debug,
+        log::LevelFilter::Debug | log::LevelFilter::Trace => log::LevelFilter::Trace,
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("cannot read {}: {1}", .0.display())]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("invalid configuration file {}: {1}", .0.display())]
+    Parse(PathBuf, #[source] toml::de::Error),
+    #[error("interface prefix {0:?} should be 1 to 7 letters, digits, '-' or '_'")]
+    InterfacePrefix(String),
+}
+
+/// The effective settings of the plugin.
+#[derive(Debug)]
+pub(crate) struct Settings {
+    pub(crate) plugin_name: String,
+    pub(crate) socket: PathBuf,
+    pub(crate) db_dir: PathBuf,
+    pub(crate) conf_dir: PathBuf,
+    pub(crate) logging: LogSettings,
+    pub(crate) netns: NetworkNamespaceOptions,
+    pub(crate) interface_prefix: String,
+    pub(crate) metrics_listen: Option<String>,
+    pub(crate) watchdog: WatchdogSettings,
+    pub(crate) shutdown_timeout: Duration,
+}
+
+impl Settings {
+    /// Combine the options from the command line and the environment with
+    /// the ones from the configuration file, if there is one.
+    pub(crate) fn load(config: Option<&Path>, options: Options) -> Result<Self, Error> {
+        let file_options = match config {
+            Some(path) => Options::from_file(path)?,
+            None => Options::default(),
+        };
+        let legacy_options = Options::from_legacy_env(std::env::var_os);
+        Self::resolve(options.or(legacy_options).or(file_options))
+    }
+
+    /// The configuration directory alone, for the `check` subcommand, which
+    /// doesn't need the other settings to be valid. The configuration file
+    /// is only read if the directory is not given otherwise.
+    pub(crate) fn conf_dir(config: Option<&Path>, options: Options) -> Result<PathBuf, Error> {
+        let conf_dir = match (options.conf_dir, config) {
+            (Some(conf_dir), _) => Some(conf_dir),
+            (None, Some(path)) => Options::from_file(path)?.conf_dir,
+            (None, None) => None,
+        };
+        Ok(conf_dir.unwrap_or_else(|| DEFAULT_CONF_DIR.into()))
+    }
+
+    fn resolve(options: Options) -> Result<Self, Error> {
+        let plugin_name = options
+            .plugin_name
+            .unwrap_or_else(|| DEFAULT_PLUGIN_NAME.to_owned());
+        let socket = options
+            .socket
+            .unwrap_or_else(|| format!("/run/docker/plugins/{plugin_name}.sock").into());
+
+        let level = match options.log_level {
+            Some(level) => level.into(),
+            None if cfg!(debug_assertions) => log::LevelFilter::Debug,
+            None => log::LevelFilter::Warn,
+        };
+        let level = match (options.log_level, options.debug) {
+            (None, Some(true)) => more_verbose(level),
+            _ => level,
+        };
+        let output = match options.log_file {
+            None => LogOutput::Stderr,
+            Some(path) if path == Path::new("stderr") => LogOutput::Stderr,
+            Some(path) if path == Path::new("stdout") => LogOutput::Stdout,
+            Some(path) => LogOutput::File(path),
+        };
+
+        let interface_prefix = options
+            .interface_prefix
+            .unwrap_or_else(|| DEFAULT_INTERFACE_PREFIX.to_owned());
+        let valid_prefix = (1..=MAX_INTERFACE_PREFIX_LEN).contains(&interface_prefix.len())
+            && interface_prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_prefix {
+            return Err(Error::InterfacePrefix(interface_prefix));
+        }
+
+        Ok(Settings {
+            plugin_name,
+            socket,
+            db_dir: options.db_dir.unwrap_or_else(|| DEFAULT_DB_DIR.into()),
+            conf_dir: options.conf_dir.unwrap_or_else(|| DEFAULT_CONF_DIR.into()),
+            logging: LogSettings {
+                level,
+                format: options.log_format.unwrap_or_default(),
+                output,
+            },
+            netns: NetworkNamespaceOptions::from_mode(
+                options.netns.as_deref(),
+                options.netns_auto_path,
+            ),
+            interface_prefix,
+            metrics_listen: options.metrics_listen,
+            watchdog: WatchdogSettings {
+                timeout: options.handshake_timeout.map(Duration::from_secs),
+                recover: options.handshake_recover.unwrap_or(false),
+            },
+            shutdown_timeout: options
+                .shutdown_timeout
+                .map_or(DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs),
+        })
+    }
+
+    /// Log the effective settings, once logging is configured.
+    pub(crate) fn log(&self) {
+        log::info!(
+            plugin_name = self.plugin_name.as_str(),
+            socket:display = self.socket.display(),
+            db_dir:display = self.db_dir.display(),
+            conf_dir:display = self.conf_dir.display(),
+            log_level:display = self.logging.level,
+            log_format:? = self.logging.format,
+            log_output:? = self.logging.output,
+            netns:? = self.netns,
+            interface_prefix = self.interface_prefix.as_str(),
+            metrics_listen:? = self.metrics_listen,
+            handshake_timeout:? = self.watchdog.timeout,
+            handshake_recover = self.watchdog.recover,
+            shutdown_timeout:? = self.shutdown_timeout;
+            "Effective settings"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence() {
+        let cli = Cli::try_parse_from([
+            "wireguard-docker-plugin",
+            "--db-dir=/var/lib/wireguard",
+            "--handshake-recover",
+        ])
+        .unwrap();
+        let file: Options = toml::from_str(
+            r#"
+            plugin-name = "wg"
+            db-dir = "/srv/wireguard_db"
+            interface-prefix = "wgx"
+            handshake-timeout = 300
+            "#,
+        )
+        .unwrap();
+        let settings = Settings::resolve(cli.options.or(file)).unwrap();
+        assert_eq!(settings.plugin_name, "wg");
+        assert_eq!(settings.socket, Path::new("/run/docker/plugins/wg.sock"));
+        assert_eq!(settings.db_dir, Path::new("/var/lib/wireguard"));
+        assert_eq!(settings.interface_prefix, "wgx");
+        assert_eq!(settings.watchdog.timeout, Some(Duration::from_secs(300)));
+        assert!(settings.watchdog.recover);
+
+        let file = toml::from_str::<Options>("socket-path = \"/run/wg.sock\"");
+        assert!(file.is_err());
+
+        let options = Options {
+            interface_prefix: Some("wireguard".to_owned()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Settings::resolve(options),
+            Err(Error::InterfacePrefix(_))
+        ));
+    }
+
+    #[test]
+    fn test_conf_dir() {
+        // other settings don't matter, and the file is not read
+        let cli = Cli::try_parse_from([
+            "wireguard-docker-plugin",
+            "--config=/nonexistent/wireguard.toml",
+            "--interface-prefix=wireguard",
+            "check",
+            "--conf-dir=/etc/wireguard",
+        ])
+        .unwrap();
+        let conf_dir = Settings::conf_dir(cli.config.as_deref(), cli.options).unwrap();
+        assert_eq!(conf_dir, Path::new("/etc/wireguard"));
+
+        let conf_dir = Settings::conf_dir(None, Options::default()).unwrap();
+        assert_eq!(conf_dir, Path::new(DEFAULT_CONF_DIR));
+        let config = Path::new("/nonexistent/wireguard.toml");
+        let result = Settings::conf_dir(Some(config), Options::default());
+        assert!(matches!(result, Err(Error::Read(..))));
+    }
+
+    #[test]
+    fn test_legacy_env() {
+        fn legacy(vars: &[(&str, &str)]) -> Options {
+            Options::from_legacy_env(|name| {
+                let (_, value) = vars.iter().find(|(var, _)| *var == name)?;
+                Some(OsString::from(value))
+            })
+        }
+        let options = legacy(&[("DEBUG", "1"), ("LOGFILE", "stdout")]);
+        assert_eq!(options.debug, Some(true));
+        assert_eq!(options.log_file.as_deref(), Some(Path::new("stdout")));
+        // the prefixed variables, or flags, come first
+        let cli = Cli::try_parse_from(["wireguard-docker-plugin", "--log-file=/var/log/wg.log"]);
+        let settings = Settings::resolve(cli.unwrap().options.or(options)).unwrap();
+        assert_eq!(
+            settings.logging.output,
+            LogOutput::File("/var/log/wg.log".into())
+        );
+        // anything but `1` is ignored, rather than rejected
+        for value in ["0", "yes", "verbose", ""] {
+            assert_eq!(legacy(&[("DEBUG", value)]).debug, None, "{value}");
+        }
+        assert_eq!(legacy(&[]).log_file, None);
+    }
+}
@@ -0,0 +1,87 @@
+//! Integration with systemd, or another service manager: socket activation
+//! as in `sd_listen_fds(3)`, and notifications as in `sd_notify(3)`.
+
+use std::os::fd::{BorrowedFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::time::Duration;
+
+/// The first file descriptor passed by the service manager.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the first socket passed by the service manager, if it started the
+/// plugin through socket activation.
+///
+/// The environment variables are removed, so that they are not inherited by
+/// child processes. Call this before starting other threads.
+pub(crate) fn take_listener() -> Option<UnixListener> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if pid?.parse::<u32>().ok()? != std::process::id() {
+        return None;
+    }
+    let fds: RawFd = fds?.parse().ok()?;
+    if fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        log::warn!(fds; "Only the first socket passed by the service manager is used");
+    }
+
+    // SAFETY: the service manager passes open file descriptors, starting
+    // from LISTEN_FDS_START, and nothing else in the process owns them
+    let fd = unsafe { BorrowedFd::borrow_raw(LISTEN_FDS_START) };
+    if let Err(err) = rustix::io::fcntl_setfd(fd, rustix::io::FdFlags::CLOEXEC) {
+        log::warn!(err:display; "Failed to set close-on-exec on the passed socket");
+    }
+    // SAFETY: as above
+    Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// Send a state change, such as `READY=1`, to the service manager, if it
+/// asked for notifications with `NOTIFY_SOCKET`.
+pub(crate) fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let send = || {
+        let socket = UnixDatagram::unbound()?;
+        match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &address)
+            }
+            None => socket.send_to(state.as_bytes(), &path),
+        }
+    };
+    if let Err(err) = send() {
+        log::warn!(err:display, state; "Failed to notify the service manager");
+    }
+}
+
+/// How often to send `WATCHDOG=1`, if the service manager enabled the
+/// watchdog for this process: half of the timeout, as recommended.
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec) / 2)
+}
+
+/// Keep the service manager watchdog from firing, for as long as the
+/// runtime is responsive.
+pub(crate) async fn keep_alive(interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
@@ -1,20 +1,99 @@
-use std::{net::SocketAddr, num::NonZeroU16, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroU16,
+    path::{Path, PathBuf},
+};
 
 use super::{WgError, WgErrorInner};
 
-#[derive(Debug, Clone)]
+/// A WireGuard key: private, public or preshared. Keys are zeroized when
+/// dropped, and their `Debug` output is redacted, so that logging a
+/// configuration doesn't leak secrets. `Display` gives the base64 encoding.
+#[derive(Clone, PartialEq, Eq)]
 pub(crate) struct Key([u8; 32]);
 
+impl Drop for Key {
+    fn drop(&mut self) {
+        use zeroize::Zeroize;
+        self.0.zeroize();
+    }
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(<redacted>)")
+    }
+}
+
 impl From<Key> for [u8; 32] {
     fn from(key: Key) -> Self {
         key.0
     }
 }
 
+impl From<[u8; 32]> for Key {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
 impl Key {
+    /// Generate a new private key, clamped like `wg genkey` does.
+    pub(crate) fn generate() -> Self {
+        let mut key = Self(x25519_dalek::StaticSecret::random().to_bytes());
+        key.0[0] &= 248;
+        key.0[31] = (key.0[31] & 127) | 64;
+        key
+    }
+
     pub(crate) fn bytes(&self) -> &[u8; 32] {
         &self.0
     }
+
+    /// The public key for this private key.
+    pub(crate) fn public_key(&self) -> Key {
+        let secret = x25519_dalek::StaticSecret::from(self.0);
+        Self(x25519_dalek::PublicKey::from(&secret).to_bytes())
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use base64::prelude::*;
+        f.write_str(&BASE64_STANDARD.encode(self.0))
+    }
+}
+
+/// Keys are stored as their base64 encoding.
+impl serde::Serialize for Key {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Key {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyVisitor;
+
+        impl serde::de::Visitor<'_> for KeyVisitor {
+            type Value = Key;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("a base64 encoded key")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Key, E> {
+                // the value is not repeated in the error, since it may be secret
+                s.parse().map_err(|()| {
+                    E::invalid_value(serde::de::Unexpected::Other("a bad key"), &self)
+                })
+            }
+        }
+
+        deserializer.deserialize_str(KeyVisitor)
+    }
 }
 
 impl std::str::FromStr for Key {
@@ -23,43 +102,423 @@ impl std::str::FromStr for Key {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let s = s.trim();
         use base64::prelude::*;
-        let mut bytes = [0; 32];
-        BASE64_STANDARD
-            .decode_slice(s, &mut bytes)
+        // decoded in place, so that no copy is left behind
+        let mut key = Self([0; 32]);
+        let len = BASE64_STANDARD
+            .decode_slice(s, &mut key.0)
             .map_err(|_| ())?;
-        Ok(Self(bytes))
+        if len != key.0.len() {
+            return Err(());
+        }
+        Ok(key)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct Config {
-    pub(super) private_key: Key,
+    /// `None` for configurations with an address pool, until a generated
+    /// key is set with [`Config::set_identity`].
+    pub(super) private_key: Option<Key>,
     pub(super) listen_port: Option<u16>,
     pub(super) fw_mark: Option<u32>,
-    pub(super) address: Option<CidrAddress>,
+    pub(super) addresses: Vec<CidrAddress>,
+    pub(super) mtu: Option<u32>,
+    pub(super) dns_servers: Vec<IpAddr>,
+    pub(super) dns_search: Vec<String>,
     pub(super) peers: Vec<Peer>,
+    /// Addresses for endpoints with generated keys (`AddressPool`).
+    pub(super) address_pool: Option<CidrAddress>,
 }
 
 impl Config {
-    pub(crate) fn address(&self) -> Option<&CidrAddress> {
-        self.address.as_ref()
+    pub(crate) fn addresses(&self) -> &[CidrAddress] {
+        &self.addresses
+    }
+
+    /// The public key of the interface, if the private key is known.
+    pub(crate) fn public_key(&self) -> Option<Key> {
+        self.private_key.as_ref().map(Key::public_key)
+    }
+
+    pub(crate) fn address_pool(&self) -> Option<&CidrAddress> {
+        self.address_pool.as_ref()
+    }
+
+    /// Use the key and addresses of an endpoint, for configurations with an
+    /// address pool.
+    pub(crate) fn set_identity(&mut self, private_key: Key, addresses: Vec<CidrAddress>) {
+        self.private_key = Some(private_key);
+        self.addresses = addresses;
+    }
+
+    /// The addresses that can be reported to Docker: the first IPv4 and the
+    /// first IPv6 address, since Docker only supports one of each.
+    pub(crate) fn primary_addresses(&self) -> (Option<&CidrAddress>, Option<&CidrAddress>) {
+        let ipv4 = self.addresses.iter().find(|addr| addr.ip().is_ipv4());
+        let ipv6 = self.addresses.iter().find(|addr| addr.ip().is_ipv6());
+        (ipv4, ipv6)
+    }
+
+    /// The addresses that are not reported to Docker, and need to be added
+    /// to the interface by the plugin itself.
+    pub(crate) fn secondary_addresses(&self) -> impl Iterator<Item = &CidrAddress> {
+        let (ipv4, ipv6) = self.primary_addresses();
+        self.addresses.iter().filter(move |addr| {
+            !ipv4.is_some_and(|primary| std::ptr::eq(*addr, primary))
+                && !ipv6.is_some_and(|primary| std::ptr::eq(*addr, primary))
+        })
+    }
+
+    pub(crate) fn mtu(&self) -> Option<u32> {
+        self.mtu
+    }
+
+    pub(crate) fn dns_servers(&self) -> &[IpAddr] {
+        &self.dns_servers
+    }
+
+    pub(crate) fn dns_search(&self) -> &[String] {
+        &self.dns_search
     }
 
     pub(crate) fn routes(&self) -> impl Iterator<Item = &CidrAddress> {
         self.peers.iter().flat_map(|peer| peer.allowed_ips.iter())
     }
+
+    /// Resolve the hostnames of peer endpoints. This fails if any of them
+    /// can't be resolved.
+    pub(crate) fn resolve_endpoints(&mut self, resolver: &dyn Resolver) -> Result<(), WgError> {
+        for endpoint in self
+            .peers
+            .iter_mut()
+            .filter_map(|peer| peer.endpoint.as_mut())
+        {
+            endpoint
+                .resolve(resolver)
+                .map_err(|err| WgErrorInner::Resolve(endpoint.to_string(), err))?;
+        }
+        Ok(())
+    }
+
+    /// Resolve the hostnames of peer endpoints again, and return whether any
+    /// address changed. Endpoints that can't be resolved keep their previous
+    /// address.
+    pub(crate) fn reresolve_endpoints(&mut self, resolver: &dyn Resolver) -> bool {
+        let mut changed = false;
+        for endpoint in self
+            .peers
+            .iter_mut()
+            .filter_map(|peer| peer.endpoint.as_mut())
+        {
+            let previous = endpoint.addr().copied();
+            if let Err(err) = endpoint.resolve(resolver) {
+                log::warn!(err:display, endpoint:display; "Failed to resolve peer endpoint");
+            }
+            changed |= endpoint.addr().copied() != previous;
+        }
+        changed
+    }
+
+    pub(crate) fn has_hostname_endpoints(&self) -> bool {
+        self.peers
+            .iter()
+            .any(|peer| matches!(peer.endpoint, Some(PeerEndpoint::Host { .. })))
+    }
+
+    /// A hash of the settings, used to tell which version of a configuration
+    /// was applied to an interface. Since it is shown to clients, secrets
+    /// only go in hashed: the private key as its public key, and preshared
+    /// keys through a hash of their own.
+    pub(crate) fn digest(&self) -> String {
+        use sha2::{Digest, Sha256};
+        let mut hasher = Sha256::new();
+        let public_key = self.private_key.as_ref().map(Key::public_key);
+        hasher.update(public_key.as_ref().map_or(&[0; 32], Key::bytes));
+        hasher.update(format!(
+            "{:?} {:?} {:?}\n",
+            self.listen_port, self.fw_mark, self.mtu
+        ));
+        for address in &self.addresses {
+            hasher.update(format!("{address} "));
+        }
+        hasher.update("\n");
+        for server in &self.dns_servers {
+            hasher.update(format!("{server} "));
+        }
+        for domain in &self.dns_search {
+            hasher.update(format!("{domain} "));
+        }
+        hasher.update("\n");
+        for peer in &self.peers {
+            hasher.update(peer.public_key.bytes());
+            match &peer.preshared_key {
+                Some(psk) => hasher.update(
+                    Sha256::new_with_prefix("preshared key\n")
+                        .chain_update(psk.bytes())
+                        .finalize(),
+                ),
+                None => hasher.update([0; 32]),
+            }
+            let endpoint = peer.endpoint.as_ref().map(ToString::to_string);
+            hasher.update(format!("{endpoint:?} {:?}", peer.persistent_keepalive));
+            for allowed_ip in &peer.allowed_ips {
+                hasher.update(format!(" {allowed_ip}"));
+            }
+            hasher.update("\n");
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// A `[Peer]` section that the other side of the tunnel can use to add
+    /// this interface as a peer, headed by a `name` comment. The preshared
+    /// key is only included when there is a single peer, since otherwise we
+    /// can't tell which one is asking.
+    ///
+    /// Returns `None` if there is no private key yet.
+    pub(crate) fn export_peer(&self, name: &str) -> Option<String> {
+        let peer = Peer {
+            public_key: self.public_key()?,
+            preshared_key: match &self.peers[..] {
+                [peer] => peer.preshared_key.clone(),
+                _ => None,
+            },
+            endpoint: None,
+            allowed_ips: self
+                .addresses
+                .iter()
+                .map(|address| CidrAddress {
+                    ip: address.ip,
+                    cidr: match address.ip {
+                        IpAddr::V4(_) => Ipv4Addr::BITS as u8,
+                        IpAddr::V6(_) => Ipv6Addr::BITS as u8,
+                    },
+                })
+                .collect(),
+            persistent_keepalive: None,
+        };
+        Some(format!("# {name}\n{peer}"))
+    }
+
+    /// Compare the WireGuard settings of two configurations. Settings that
+    /// are only applied when the interface is created (addresses, MTU, DNS)
+    /// are not compared.
+    pub(crate) fn diff<'a>(&self, new: &'a Config) -> ConfigDiff<'a> {
+        let removed_peers = self
+            .peers
+            .iter()
+            .filter(|old| !new.peers.iter().any(|p| p.public_key == old.public_key))
+            .map(|old| old.public_key.clone())
+            .collect();
+        let changed_peers = new
+            .peers
+            .iter()
+            .filter(|peer| !self.peers.contains(peer))
+            .collect();
+        ConfigDiff {
+            private_key: new
+                .private_key
+                .as_ref()
+                .filter(|_| self.private_key != new.private_key),
+            listen_port: (self.listen_port != new.listen_port).then_some(new.listen_port),
+            fw_mark: (self.fw_mark != new.fw_mark).then_some(new.fw_mark),
+            removed_peers,
+            changed_peers,
+        }
+    }
+}
+
+/// Changes between two versions of a configuration, see [`Config::diff`].
+#[derive(Debug)]
+pub(crate) struct ConfigDiff<'a> {
+    pub(super) private_key: Option<&'a Key>,
+    pub(super) listen_port: Option<Option<u16>>,
+    pub(super) fw_mark: Option<Option<u32>>,
+    pub(super) removed_peers: Vec<Key>,
+    /// Peers that are new, or have any setting changed.
+    pub(super) changed_peers: Vec<&'a Peer>,
+}
+
+impl ConfigDiff<'_> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.private_key.is_none()
+            && self.listen_port.is_none()
+            && self.fw_mark.is_none()
+            && self.removed_peers.is_empty()
+            && self.changed_peers.is_empty()
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct Peer {
     pub(super) public_key: Key,
     pub(super) preshared_key: Option<Key>,
-    pub(super) endpoint: Option<SocketAddr>,
+    pub(super) endpoint: Option<PeerEndpoint>,
     pub(super) allowed_ips: Vec<CidrAddress>,
     pub(super) persistent_keepalive: Option<NonZeroU16>,
 }
 
-#[derive(Debug, Clone)]
+/// Write the configuration in wg-quick syntax, which `parse_config` reads
+/// back to the same configuration. Keys given as files or environment
+/// variables are written out.
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "[Interface]")?;
+        if let Some(private_key) = &self.private_key {
+            writeln!(f, "PrivateKey = {private_key}")?;
+        }
+        if let Some(listen_port) = self.listen_port {
+            writeln!(f, "ListenPort = {listen_port}")?;
+        }
+        if let Some(fw_mark) = self.fw_mark {
+            writeln!(f, "FwMark = {fw_mark}")?;
+        }
+        if !self.addresses.is_empty() {
+            writeln!(f, "Address = {}", join(&self.addresses))?;
+        }
+        if let Some(address_pool) = &self.address_pool {
+            writeln!(f, "AddressPool = {address_pool}")?;
+        }
+        if let Some(mtu) = self.mtu {
+            writeln!(f, "MTU = {mtu}")?;
+        }
+        if !self.dns_servers.is_empty() || !self.dns_search.is_empty() {
+            let servers = self.dns_servers.iter().map(ToString::to_string);
+            let dns: Vec<_> = servers.chain(self.dns_search.iter().cloned()).collect();
+            writeln!(f, "DNS = {}", dns.join(", "))?;
+        }
+        for peer in &self.peers {
+            write!(f, "\n{peer}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "[Peer]")?;
+        writeln!(f, "PublicKey = {}", self.public_key)?;
+        if let Some(preshared_key) = &self.preshared_key {
+            writeln!(f, "PresharedKey = {preshared_key}")?;
+        }
+        if let Some(endpoint) = &self.endpoint {
+            writeln!(f, "Endpoint = {endpoint}")?;
+        }
+        if !self.allowed_ips.is_empty() {
+            writeln!(f, "AllowedIPs = {}", join(&self.allowed_ips))?;
+        }
+        if let Some(persistent_keepalive) = self.persistent_keepalive {
+            writeln!(f, "PersistentKeepalive = {persistent_keepalive}")?;
+        }
+        Ok(())
+    }
+}
+
+fn join(items: &[impl ToString]) -> String {
+    let items: Vec<_> = items.iter().map(ToString::to_string).collect();
+    items.join(", ")
+}
+
+/// The endpoint of a peer, as written in the configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PeerEndpoint {
+    Addr(SocketAddr),
+    /// A hostname, and the address it resolved to the last time.
+    Host {
+        host: String,
+        port: u16,
+        resolved: Option<SocketAddr>,
+    },
+}
+
+impl PeerEndpoint {
+    /// The address to give to WireGuard, if known.
+    pub(crate) fn addr(&self) -> Option<&SocketAddr> {
+        match self {
+            PeerEndpoint::Addr(addr) => Some(addr),
+            PeerEndpoint::Host { resolved, .. } => resolved.as_ref(),
+        }
+    }
+
+    /// Resolve a hostname endpoint. The current address is kept if it's
+    /// still valid, so that peers don't flip between addresses.
+    fn resolve(&mut self, resolver: &dyn Resolver) -> std::io::Result<()> {
+        let PeerEndpoint::Host {
+            host,
+            port,
+            resolved,
+        } = self
+        else {
+            return Ok(());
+        };
+        let addrs = resolver.resolve(host, *port)?;
+        if resolved.is_some_and(|addr| addrs.contains(&addr)) {
+            return Ok(());
+        }
+        match addrs.first() {
+            Some(addr) => {
+                *resolved = Some(*addr);
+                Ok(())
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no addresses found for {host}"),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for PeerEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerEndpoint::Addr(addr) => addr.fmt(f),
+            PeerEndpoint::Host { host, port, .. } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+impl std::str::FromStr for PeerEndpoint {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(PeerEndpoint::Addr(addr));
+        }
+        let (host, port) = s.rsplit_once(':').ok_or(())?;
+        let valid_host = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+        if !valid_host {
+            return Err(());
+        }
+        Ok(PeerEndpoint::Host {
+            host: host.to_owned(),
+            port: port.parse().map_err(|_| ())?,
+            resolved: None,
+        })
+    }
+}
+
+/// Resolves hostnames in peer endpoints.
+pub(crate) trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolver that uses the system resolver (`getaddrinfo`). It blocks.
+pub(crate) struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        use std::net::ToSocketAddrs;
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct CidrAddress {
     ip: std::net::IpAddr,
     cidr: u8,
@@ -73,6 +532,60 @@ impl CidrAddress {
     pub(crate) fn cidr(&self) -> u8 {
         self.cidr
     }
+
+    /// Whether `other` is in this subnet. Addresses of different families
+    /// are never contained.
+    pub(crate) fn contains(&self, other: &CidrAddress) -> bool {
+        if self.cidr > other.cidr {
+            return false;
+        }
+        match (self.ip, other.ip) {
+            (IpAddr::V4(ip), IpAddr::V4(other)) => {
+                let host_bits = Ipv4Addr::BITS.saturating_sub(self.cidr.into());
+                let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(other) & mask
+            }
+            (IpAddr::V6(ip), IpAddr::V6(other)) => {
+                let host_bits = Ipv6Addr::BITS.saturating_sub(self.cidr.into());
+                let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(other) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether one of the two subnets contains the other.
+    pub(crate) fn overlaps(&self, other: &CidrAddress) -> bool {
+        self.contains(other) || other.contains(self)
+    }
+
+    /// The first address of this subnet that is not in `used`, with the
+    /// same prefix length. The network address and the IPv4 broadcast
+    /// address are never returned.
+    pub(crate) fn allocate(&self, used: &[IpAddr]) -> Option<CidrAddress> {
+        let (network, bits) = match self.ip {
+            IpAddr::V4(ip) => (u128::from(u32::from(ip)), Ipv4Addr::BITS),
+            IpAddr::V6(ip) => (u128::from(ip), Ipv6Addr::BITS),
+        };
+        let host_bits = bits.checked_sub(self.cidr.into())?;
+        let size = 1u128.checked_shl(host_bits).unwrap_or(u128::MAX);
+        let network = network & !(size - 1);
+        let last = match self.ip {
+            // no broadcast in point-to-point subnets
+            IpAddr::V4(_) if host_bits > 1 => size - 1,
+            _ => size,
+        };
+        (1..last)
+            .map(|offset| match self.ip {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from((network + offset) as u32)),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(network + offset)),
+            })
+            .find(|ip| !used.contains(ip))
+            .map(|ip| CidrAddress {
+                ip,
+                cidr: self.cidr,
+            })
+    }
 }
 
 impl std::fmt::Display for CidrAddress {
@@ -108,189 +621,697 @@ async fn load_config_from_path(path: impl AsRef<std::path::Path>) -> Result<Conf
 }
 
 fn parse_config(text: &str) -> Result<Config, WgError> {
-    let parser = ini_core::Parser::new(text)
-        .comment_char(b'#')
-        .auto_trim(true);
-
-    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-    enum Section {
-        None,
-        Interface,
-        Peer,
-    }
-
-    let mut current_section = Section::None;
-
-    let mut private_key = None;
-    let mut listen_port = None;
-    let mut fw_mark = None;
-    let mut address = None;
-    let mut peers = Vec::new();
-    let mut public_key = None;
-    let mut preshared_key = None;
-    let mut endpoint = None;
-    let mut allowed_ips = Vec::new();
-    let mut persistent_keepalive = None;
-    let mut peer_section_line = 1;
-
-    for (i, item) in parser.enumerate() {
-        let line = i + 1;
-        match item {
-            ini_core::Item::Error(s) => {
-                return Err(WgErrorInner::ConfigParse(format!("line {line}: {s}")).into());
+    config_or_error(parse_config_diagnostics(text))
+}
+
+/// Parse configuration text given in the network options. Keys can't be
+/// read from files or environment variables, otherwise anyone who can
+/// create a network could use the keys of the plugin, such as those of
+/// other networks.
+fn parse_inline_config(text: &str) -> Result<Config, WgError> {
+    let mut parser = ConfigParser::new(text);
+    parser.key_indirection = false;
+    config_or_error(parser.finish())
+}
+
+fn config_or_error(
+    (config, diagnostics): (Option<Config>, Vec<Diagnostic>),
+) -> Result<Config, WgError> {
+    match config {
+        Some(config) => {
+            for warning in &diagnostics {
+                log::warn!(warning:display; "Configuration warning");
             }
-            ini_core::Item::Section(section_name) => match section_name {
-                "Interface" => current_section = Section::Interface,
-                "Peer" => {
-                    peer_section_line = line;
-                    current_section = Section::Peer;
-                }
-                _ => {
-                    return Err(WgErrorInner::ConfigParse(format!(
-                        "line {line}: unexpected section {section_name}"
-                    ))
-                    .into())
-                }
-            },
-            ini_core::Item::SectionEnd => {
-                if let Section::Peer = current_section {
-                    peers.push(Peer {
-                        public_key: public_key.ok_or_else(|| {
-                            WgErrorInner::ConfigParse(format!(
-                                "line {peer_section_line}: Peer section missing PublicKey"
-                            ))
-                        })?,
-                        preshared_key,
-                        endpoint,
-                        allowed_ips,
-                        persistent_keepalive,
-                    });
-                    public_key = None;
-                    preshared_key = None;
-                    endpoint = None;
-                    allowed_ips = Vec::new();
-                    persistent_keepalive = None;
-                }
-                current_section = Section::None;
+            Ok(config)
+        }
+        None => Err(WgErrorInner::Diagnostics(Diagnostics(diagnostics)).into()),
+    }
+}
+
+/// How serious a [`Diagnostic`] is. Any error makes the configuration
+/// unusable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Severity {
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// What a [`Diagnostic`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DiagnosticKind {
+    /// A line that is not a section header, a property or a comment.
+    Syntax,
+    UnknownSection,
+    UnknownKey,
+    InvalidValue,
+    MissingKey,
+    /// A key that is set more than once in the same section.
+    DuplicateKey,
+    /// A key that wg-quick understands, but the plugin ignores.
+    IgnoredKey,
+    /// Keys that can't be used together.
+    ConflictingKeys,
+    /// A key file that doesn't exist, or can't be read.
+    KeyFileUnreadable,
+    /// A key file that is readable by group or others.
+    KeyFilePermissions,
+    /// An environment variable for a key that is not set.
+    KeyEnvMissing,
+    /// A key read from a file or an environment variable, where keys must
+    /// be given directly.
+    KeyIndirection,
+    /// Peers with the same public key.
+    DuplicatePublicKey,
+    /// AllowedIPs of different peers that include each other.
+    OverlappingAllowedIps,
+    /// An address that is not in the AllowedIPs of any peer.
+    UnroutedAddress,
+    /// A peer that has no endpoint, and no port to connect to.
+    UnreachablePeer,
+}
+
+/// Where a [`Diagnostic`] points to in the configuration text. Lines and
+/// columns start from 1, and columns count characters. The end column is
+/// excluded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Span {
+    pub(crate) line: usize,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+/// A problem found while parsing a configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Diagnostic {
+    pub(crate) severity: Severity,
+    pub(crate) kind: DiagnosticKind,
+    /// `None` for problems with the configuration as a whole.
+    pub(crate) span: Option<Span>,
+    pub(crate) section: Option<&'static str>,
+    pub(crate) key: Option<String>,
+    pub(crate) message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.severity)?;
+        if let Some(span) = &self.span {
+            write!(f, "line {}, column {}: ", span.line, span.start)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// The diagnostics of a configuration that could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Diagnostics(pub(crate) Vec<Diagnostic>);
+
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
             }
-            ini_core::Item::Property(property, Some(value)) => match (current_section, property) {
-                (Section::Interface, "PrivateKey") => {
-                    let key: Key = value.parse().map_err(|_| {
-                        WgErrorInner::ConfigParse(format!(
-                            "line {line}: key should be a valid 256-bit base64 string"
-                        ))
-                    })?;
-                    private_key = Some(key);
-                }
-                (Section::Interface, "ListenPort") => {
-                    listen_port = if let Some(hex) = value.strip_prefix("0x") {
-                        let port: u16 = u16::from_str_radix(hex, 16).map_err(|_| {
-                            WgErrorInner::ConfigParse(format!(
-                                "line {line}: ListenPort should be a valid port number"
-                            ))
-                        })?;
-                        Some(port)
-                    } else if value == "off" {
-                        None
-                    } else {
-                        let port: u16 = value.parse().map_err(|_| {
-                            WgErrorInner::ConfigParse(format!(
-                                "line {line}: ListenPort should be a valid port number"
-                            ))
-                        })?;
-                        Some(port)
-                    };
-                }
-                (Section::Interface, "FwMark") => {
-                    let mark: u32 = value.parse().map_err(|_| {
-                        WgErrorInner::ConfigParse(format!(
-                            "line {line}: FwMark should be a valid integer"
-                        ))
-                    })?;
-                    fw_mark = Some(mark);
+            diagnostic.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keys of the `[Interface]` section that only make sense to wg-quick.
+const WG_QUICK_KEYS: &[&str] = &[
+    "Table",
+    "PreUp",
+    "PostUp",
+    "PreDown",
+    "PostDown",
+    "SaveConfig",
+];
+
+/// MTUs that can be set: from the minimum MTU of IPv6 links, to the largest
+/// MTU of any link.
+pub(crate) const MTU_RANGE: std::ops::RangeInclusive<u32> = 1280..=65535;
+
+/// Keys that can be repeated, adding to the previous values.
+const LIST_KEYS: &[&str] = &["Address", "DNS", "AllowedIPs"];
+
+/// Parse a configuration, collecting all the errors and warnings instead of
+/// stopping at the first one. The configuration is only returned if there
+/// are no errors.
+pub(crate) fn parse_config_diagnostics(text: &str) -> (Option<Config>, Vec<Diagnostic>) {
+    ConfigParser::new(text).finish()
+}
+
+/// Parse a configuration like [`parse_config_diagnostics`] does, then look
+/// for mistakes that the plugin accepts, but that most likely keep the
+/// tunnel from working, such as peers sharing the same AllowedIPs.
+pub(crate) fn check_config(text: &str) -> Vec<Diagnostic> {
+    let mut parser = ConfigParser::new(text);
+    let config = parser.parse();
+    // checking a configuration with errors would report the same problems again
+    if let Some(config) = config.filter(|_| !parser.has_errors()) {
+        parser.check(&config);
+    }
+    parser.diagnostics
+}
+
+struct ConfigParser<'a> {
+    text: &'a str,
+    section: Option<&'static str>,
+    diagnostics: Vec<Diagnostic>,
+    /// Where each address of the configuration is written, for [`check_config`].
+    address_values: Vec<&'a str>,
+    /// Where each peer of the configuration is written, for [`check_config`].
+    peer_values: Vec<PeerValues<'a>>,
+    /// Whether keys can be read from files and environment variables.
+    key_indirection: bool,
+}
+
+struct PeerValues<'a> {
+    header: &'a str,
+    public_key: &'a str,
+    allowed_ips: Vec<&'a str>,
+}
+
+impl<'a> ConfigParser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            section: None,
+            diagnostics: Vec::new(),
+            address_values: Vec::new(),
+            peer_values: Vec::new(),
+            key_indirection: true,
+        }
+    }
+
+    fn finish(mut self) -> (Option<Config>, Vec<Diagnostic>) {
+        let config = self.parse();
+        let failed = self.has_errors();
+        (config.filter(|_| !failed), self.diagnostics)
+    }
+
+    fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    /// The position of `s`, which must be a slice of the text.
+    fn span(&self, s: &str) -> Span {
+        let offset = s.as_ptr() as usize - self.text.as_ptr() as usize;
+        let before = &self.text[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let start = before[line_start..].chars().count() + 1;
+        Span {
+            line: before.matches('\n').count() + 1,
+            start,
+            end: start + s.chars().count(),
+        }
+    }
+
+    fn report(
+        &mut self,
+        severity: Severity,
+        kind: DiagnosticKind,
+        at: Option<&str>,
+        key: Option<&str>,
+        message: String,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            kind,
+            span: at.map(|s| self.span(s)),
+            section: self.section,
+            key: key.map(ToOwned::to_owned),
+            message,
+        });
+    }
+
+    fn error(&mut self, kind: DiagnosticKind, at: &str, key: Option<&str>, message: String) {
+        self.report(Severity::Error, kind, Some(at), key, message);
+    }
+
+    fn invalid(&mut self, value: &str, key: &str, expected: &str) {
+        let message = format!("{key} should be {expected}");
+        self.error(DiagnosticKind::InvalidValue, value, Some(key), message);
+    }
+
+    /// Parse a private or preshared key, which `*File` keys and `file:` or
+    /// `env:` values read from elsewhere.
+    fn key(&self, value: &str, key: &str) -> Result<Key, (DiagnosticKind, String)> {
+        let from_file = key.ends_with("File");
+        if !self.key_indirection
+            && (from_file || value.starts_with("file:") || value.starts_with("env:"))
+        {
+            let message =
+                format!("{key} should be a key given directly in an inline configuration");
+            return Err((DiagnosticKind::KeyIndirection, message));
+        }
+        if from_file {
+            read_key_file(value).and_then(|contents| parse_key(&contents, key))
+        } else {
+            resolve_key(value, key)
+        }
+    }
+
+    fn parse(&mut self) -> Option<Config> {
+        let parser = ini_core::Parser::new(self.text)
+            .comment_char(b'#')
+            .auto_trim(true);
+
+        let mut interface_header = None;
+        // set even if the key is invalid, so that it's not reported as missing
+        let mut has_private_key = false;
+        let mut private_key = None;
+        let mut address_pool = None;
+        let mut listen_port = None;
+        let mut fw_mark = None;
+        let mut addresses = Vec::new();
+        let mut mtu = None;
+        let mut dns_servers = Vec::new();
+        let mut dns_search = Vec::new();
+        let mut peers = Vec::new();
+
+        let mut peer_header = "";
+        let mut public_key = None;
+        let mut public_key_value = "";
+        let mut preshared_key = None;
+        let mut endpoint = None;
+        let mut allowed_ips = Vec::new();
+        let mut allowed_ips_values = Vec::new();
+        let mut persistent_keepalive = None;
+
+        // keys seen in the current section, to warn about duplicates
+        let mut seen_keys: Vec<&str> = Vec::new();
+        // properties of unknown sections are not reported
+        let mut unknown_section = false;
+
+        for item in parser {
+            match item {
+                ini_core::Item::Error(s) => {
+                    let message = format!("malformed section header {s}");
+                    self.error(DiagnosticKind::Syntax, s, None, message);
                 }
-                (Section::Interface, "Address") => {
-                    let addr: CidrAddress = value.parse().map_err(|_| {
-                        WgErrorInner::ConfigParse(format!(
-                            "line {line}: Address should be a valid address/cidr string"
-                        ))
-                    })?;
-                    address = Some(addr);
+                ini_core::Item::Section(name) => {
+                    seen_keys.clear();
+                    unknown_section = false;
+                    match name {
+                        "Interface" => {
+                            self.section = Some("Interface");
+                            interface_header.get_or_insert(name);
+                        }
+                        "Peer" => {
+                            self.section = Some("Peer");
+                            peer_header = name;
+                        }
+                        _ => {
+                            self.section = None;
+                            unknown_section = true;
+                            let message = format!("unexpected section {name}");
+                            self.error(DiagnosticKind::UnknownSection, name, None, message);
+                        }
+                    }
                 }
-                (Section::Peer, "PublicKey") => {
-                    let key: Key = value.parse().map_err(|_| {
-                        WgErrorInner::ConfigParse(format!(
-                            "line {line}: PublicKey should be a valid 256-bit base64 string"
-                        ))
-                    })?;
-                    public_key = Some(key);
+                ini_core::Item::SectionEnd => {
+                    if self.section == Some("Peer") {
+                        let peer = public_key.take().map(|public_key| Peer {
+                            public_key,
+                            preshared_key: preshared_key.take(),
+                            endpoint: endpoint.take(),
+                            allowed_ips: std::mem::take(&mut allowed_ips),
+                            persistent_keepalive: persistent_keepalive.take(),
+                        });
+                        match peer {
+                            Some(peer) => {
+                                peers.push(peer);
+                                self.peer_values.push(PeerValues {
+                                    header: peer_header,
+                                    public_key: public_key_value,
+                                    allowed_ips: std::mem::take(&mut allowed_ips_values),
+                                });
+                            }
+                            None => {
+                                let message = "Peer section missing PublicKey".to_string();
+                                self.error(DiagnosticKind::MissingKey, peer_header, None, message);
+                                preshared_key = None;
+                                endpoint = None;
+                                allowed_ips.clear();
+                                allowed_ips_values.clear();
+                                persistent_keepalive = None;
+                            }
+                        }
+                    }
+                    self.section = None;
                 }
-                (Section::Peer, "PresharedKey") => {
-                    let key: Key = value.parse().map_err(|_| {
-                        WgErrorInner::ConfigParse(format!(
-                            "line {line}: PresharedKey should be a valid 256-bit base64 string"
-                        ))
-                    })?;
-                    preshared_key = Some(key);
+                ini_core::Item::Property(key, Some(value)) => {
+                    if unknown_section {
+                        continue;
+                    }
+                    if seen_keys.contains(&key) && !LIST_KEYS.contains(&key) {
+                        let message =
+                            format!("{key} is set more than once, the last value is used");
+                        self.report(
+                            Severity::Warning,
+                            DiagnosticKind::DuplicateKey,
+                            Some(key),
+                            Some(key),
+                            message,
+                        );
+                    }
+                    seen_keys.push(key);
+                    match (self.section, key) {
+                        (Some("Interface"), "PrivateKey" | "PrivateKeyFile") => {
+                            has_private_key = true;
+                            match self.key(value, key) {
+                                Ok(parsed) => private_key = Some(parsed),
+                                Err((kind, message)) => self.error(kind, value, Some(key), message),
+                            }
+                        }
+                        (Some("Interface"), "ListenPort") => {
+                            let port = match value.strip_prefix("0x") {
+                                Some(hex) => u16::from_str_radix(hex, 16).map(Some),
+                                None if value == "off" => Ok(None),
+                                None => value.parse().map(Some),
+                            };
+                            match port {
+                                Ok(port) => listen_port = port,
+                                Err(_) => self.invalid(value, key, "a valid port number"),
+                            }
+                        }
+                        (Some("Interface"), "FwMark") => match value.parse() {
+                            Ok(mark) => fw_mark = Some(mark),
+                            Err(_) => self.invalid(value, key, "a valid integer"),
+                        },
+                        (Some("Interface"), "Address") => {
+                            for item in value.split(',').map(str::trim) {
+                                match item.parse() {
+                                    Ok(address) => {
+                                        addresses.push(address);
+                                        self.address_values.push(item);
+                                    }
+                                    Err(()) => {
+                                        self.invalid(item, key, "a valid address/cidr string")
+                                    }
+                                }
+                            }
+                        }
+                        (Some("Interface"), "AddressPool") => match value.parse() {
+                            Ok(pool) => address_pool = Some((pool, value)),
+                            Err(()) => self.invalid(value, key, "a valid address/cidr string"),
+                        },
+                        (Some("Interface"), "MTU") => match value.parse() {
+                            Ok(value) if MTU_RANGE.contains(&value) => mtu = Some(value),
+                            Ok(_) => {
+                                let expected = format!(
+                                    "between {} and {}",
+                                    MTU_RANGE.start(),
+                                    MTU_RANGE.end()
+                                );
+                                self.invalid(value, key, &expected)
+                            }
+                            Err(_) => self.invalid(value, key, "a valid integer"),
+                        },
+                        (Some("Interface"), "DNS") => {
+                            // like wg-quick, anything that is not an address is a search domain
+                            for item in value.split(',').map(str::trim) {
+                                if let Ok(addr) = item.parse::<IpAddr>() {
+                                    dns_servers.push(addr);
+                                } else if !item.is_empty() && !item.contains(char::is_whitespace) {
+                                    dns_search.push(item.to_owned());
+                                } else {
+                                    self.invalid(
+                                        item,
+                                        key,
+                                        "a list of addresses or search domains",
+                                    );
+                                }
+                            }
+                        }
+                        (Some("Interface"), key) if WG_QUICK_KEYS.contains(&key) => {
+                            let message = format!("{key} is only used by wg-quick, and is ignored");
+                            self.report(
+                                Severity::Warning,
+                                DiagnosticKind::IgnoredKey,
+                                Some(key),
+                                Some(key),
+                                message,
+                            );
+                        }
+                        (Some("Peer"), "PublicKey") => match parse_key(value, key) {
+                            Ok(parsed) => {
+                                public_key = Some(parsed);
+                                public_key_value = value;
+                            }
+                            Err((kind, message)) => self.error(kind, value, Some(key), message),
+                        },
+                        (Some("Peer"), "PresharedKey" | "PresharedKeyFile") => {
+                            match self.key(value, key) {
+                                Ok(parsed) => preshared_key = Some(parsed),
+                                Err((kind, message)) => self.error(kind, value, Some(key), message),
+                            }
+                        }
+                        (Some("Peer"), "Endpoint") => match value.parse() {
+                            Ok(value) => endpoint = Some(value),
+                            Err(()) => {
+                                self.invalid(value, key, "a valid address:port or host:port string")
+                            }
+                        },
+                        (Some("Peer"), "AllowedIPs") => {
+                            for item in value.split(',').map(str::trim) {
+                                match item.parse() {
+                                    Ok(address) => {
+                                        allowed_ips.push(address);
+                                        allowed_ips_values.push(item);
+                                    }
+                                    Err(()) => self.invalid(item, key, "a valid CIDR string"),
+                                }
+                            }
+                        }
+                        (Some("Peer"), "PersistentKeepalive") => match value.parse() {
+                            Ok(interval) => persistent_keepalive = NonZeroU16::new(interval),
+                            Err(_) if value == "off" => persistent_keepalive = None,
+                            Err(_) => self.invalid(value, key, "a valid integer"),
+                        },
+                        (None, _) => {
+                            let message = format!("property {key} outside of a section");
+                            self.error(DiagnosticKind::Syntax, key, Some(key), message);
+                        }
+                        (Some(_), _) => {
+                            let message = format!("unexpected property {key}");
+                            self.error(DiagnosticKind::UnknownKey, key, Some(key), message);
+                        }
+                    }
                 }
-                (Section::Peer, "Endpoint") => {
-                    endpoint = Some(value.parse().map_err(|_| {
-                        WgErrorInner::ConfigParse(format!(
-                            "line {line}: Endpoint should be a valid address:port string"
-                        ))
-                    })?);
+                ini_core::Item::Property(key, None) => {
+                    if !unknown_section {
+                        let message = format!("expected {key} = value");
+                        self.error(DiagnosticKind::Syntax, key, None, message);
+                    }
                 }
-                (Section::Peer, "AllowedIPs") => {
-                    allowed_ips.extend(
-                        value
-                            .split(',')
-                            .map(|s| {
-                                s.parse().map_err(|_| {
-                                    WgErrorInner::ConfigParse(format!(
-                                        "line {line}: AllowedIPs should be a valid CIDR string"
-                                    ))
-                                })
-                            })
-                            .collect::<Result<Vec<_>, _>>()?,
+                ini_core::Item::Comment(_) => {}
+                ini_core::Item::Blank => {}
+            }
+        }
+
+        // with an address pool, each endpoint gets its own key and address
+        self.section = Some("Interface");
+        match (&private_key, &address_pool) {
+            (None, None) if !has_private_key => {
+                let message = "PrivateKey is required".to_string();
+                self.report(
+                    Severity::Error,
+                    DiagnosticKind::MissingKey,
+                    interface_header,
+                    Some("PrivateKey"),
+                    message,
+                );
+            }
+            (Some(_), Some((_, at))) => {
+                let message = "PrivateKey can't be used together with AddressPool".to_string();
+                self.error(
+                    DiagnosticKind::ConflictingKeys,
+                    at,
+                    Some("AddressPool"),
+                    message,
+                );
+            }
+            (None, Some((_, at))) if !addresses.is_empty() => {
+                let message = "Address can't be used together with AddressPool".to_string();
+                self.error(
+                    DiagnosticKind::ConflictingKeys,
+                    at,
+                    Some("AddressPool"),
+                    message,
+                );
+            }
+            _ => {}
+        }
+        self.section = None;
+
+        Some(Config {
+            private_key,
+            listen_port,
+            fw_mark,
+            addresses,
+            mtu,
+            dns_servers,
+            dns_search,
+            peers,
+            address_pool: address_pool.map(|(pool, _)| pool),
+        })
+    }
+
+    /// Look for mistakes in a configuration returned by [`Self::parse`].
+    fn check(&mut self, config: &Config) {
+        let peer_values = std::mem::take(&mut self.peer_values);
+        let peers: Vec<_> = config.peers.iter().zip(&peer_values).collect();
+
+        self.section = Some("Peer");
+        for (i, (peer, values)) in peers.iter().enumerate() {
+            let previous = &peers[..i];
+
+            let duplicate = previous
+                .iter()
+                .find(|(other, _)| other.public_key == peer.public_key);
+            if let Some((_, other_values)) = duplicate {
+                let line = self.span(other_values.public_key).line;
+                let message = format!("PublicKey is the same as the peer on line {line}");
+                self.error(
+                    DiagnosticKind::DuplicatePublicKey,
+                    values.public_key,
+                    Some("PublicKey"),
+                    message,
+                );
+            }
+
+            for (allowed_ip, value) in peer.allowed_ips.iter().zip(&values.allowed_ips) {
+                let overlapping = previous.iter().find_map(|(other, other_values)| {
+                    other
+                        .allowed_ips
+                        .iter()
+                        .zip(&other_values.allowed_ips)
+                        .find(|(other_ip, _)| allowed_ip.overlaps(other_ip))
+                });
+                let Some((other_ip, other_value)) = overlapping else {
+                    continue;
+                };
+                let line = self.span(other_value).line;
+                // the kernel routes to the most specific AllowedIPs, but the
+                // same subnet can only belong to one peer
+                let (severity, message) = if allowed_ip.cidr == other_ip.cidr {
+                    let message = format!(
+                        "AllowedIPs {allowed_ip} is also used by the peer on line {line}, \
+                         only one of them gets the traffic"
                     );
-                }
-                (Section::Peer, "PersistentKeepalive") => {
-                    persistent_keepalive = if let Ok(interval) = value.parse() {
-                        NonZeroU16::new(interval)
-                    } else if value == "off" {
-                        None
-                    } else {
-                        return Err(WgErrorInner::ConfigParse(format!(
-                            "line {line}: PersistentKeepalive should be a valid integer"
-                        ))
-                        .into());
-                    };
-                }
-                (_, _) => {
-                    return Err(WgErrorInner::ConfigParse(format!(
-                        "line {line}: unexpected property {property}"
-                    ))
-                    .into())
-                }
-            },
-            ini_core::Item::Property(property, None) => {
-                return Err(WgErrorInner::ConfigParse(format!("line {line}: {property}")).into())
+                    (Severity::Error, message)
+                } else {
+                    let message =
+                        format!("AllowedIPs {allowed_ip} overlaps with {other_ip} on line {line}");
+                    (Severity::Warning, message)
+                };
+                self.report(
+                    severity,
+                    DiagnosticKind::OverlappingAllowedIps,
+                    Some(value),
+                    Some("AllowedIPs"),
+                    message,
+                );
+            }
+
+            if peer.endpoint.is_none() && config.listen_port.is_none() {
+                let message =
+                    "Peer has no Endpoint, and can't connect because there is no ListenPort"
+                        .to_string();
+                self.error(
+                    DiagnosticKind::UnreachablePeer,
+                    values.header,
+                    Some("Endpoint"),
+                    message,
+                );
+            }
+        }
+
+        self.section = Some("Interface");
+        let address_values = std::mem::take(&mut self.address_values);
+        for (address, value) in config.addresses.iter().zip(address_values) {
+            let routed = config
+                .peers
+                .iter()
+                .flat_map(|peer| &peer.allowed_ips)
+                .any(|allowed_ip| allowed_ip.overlaps(address));
+            if !routed && !config.peers.is_empty() {
+                let message = format!("Address {address} is outside the AllowedIPs of every peer");
+                self.report(
+                    Severity::Warning,
+                    DiagnosticKind::UnroutedAddress,
+                    Some(value),
+                    Some("Address"),
+                    message,
+                );
             }
-            ini_core::Item::Comment(_) => {}
-            ini_core::Item::Blank => {}
         }
+        self.section = None;
+    }
+}
+
+/// Parse a key given directly, or read it from a file or an environment
+/// variable with a `file:` or `env:` prefix.
+fn resolve_key(value: &str, property: &str) -> Result<Key, (DiagnosticKind, String)> {
+    if let Some(path) = value.strip_prefix("file:") {
+        let contents = read_key_file(path.trim())?;
+        parse_key(&contents, property)
+    } else if let Some(name) = value.strip_prefix("env:") {
+        let name = name.trim();
+        let value = std::env::var(name).map_err(|_| {
+            let err = WgErrorInner::KeyEnvMissing(name.to_owned());
+            (DiagnosticKind::KeyEnvMissing, err.to_string())
+        })?;
+        parse_key(&value, property)
+    } else {
+        parse_key(value, property)
     }
+}
 
-    Ok(Config {
-        private_key: private_key
-            .ok_or_else(|| WgErrorInner::ConfigParse("PrivateKey is required".to_string()))?,
-        listen_port,
-        fw_mark,
-        address,
-        peers,
+fn parse_key(value: &str, property: &str) -> Result<Key, (DiagnosticKind, String)> {
+    value.parse().map_err(|_| {
+        (
+            DiagnosticKind::InvalidValue,
+            format!("{property} should be a valid 256-bit base64 string"),
+        )
     })
 }
 
+/// Read a key file. Keys must not be readable by group or others.
+fn read_key_file(path: &str) -> Result<String, (DiagnosticKind, String)> {
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = Path::new(path);
+    let file_error = |err| {
+        let err = WgErrorInner::KeyFile(path.to_owned(), err);
+        (DiagnosticKind::KeyFileUnreadable, err.to_string())
+    };
+    let mut file = std::fs::File::open(path).map_err(file_error)?;
+    let mode = file.metadata().map_err(file_error)?.permissions().mode();
+    if mode & 0o044 != 0 {
+        let err = WgErrorInner::KeyFilePermissions(path.to_owned(), mode & 0o777);
+        return Err((DiagnosticKind::KeyFilePermissions, err.to_string()));
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).map_err(file_error)?;
+    Ok(contents)
+}
+
 pub(crate) struct ConfigProvider {
     inner: ConfigProviderInner,
 }
@@ -302,6 +1323,14 @@ impl ConfigProvider {
         }
     }
 
+    /// Watch for configuration changes. Returns `None` if the provider does
+    /// not support it.
+    pub fn watch(&self) -> Result<Option<ConfigWatcher>, WgError> {
+        match &self.inner {
+            ConfigProviderInner::File { base_path } => ConfigWatcher::new(base_path).map(Some),
+        }
+    }
+
     pub async fn get_config(&self, name: &str) -> Result<Config, WgError> {
         match &self.inner {
             ConfigProviderInner::File { base_path } => {
@@ -310,8 +1339,748 @@ impl ConfigProvider {
             }
         }
     }
+
+    /// Load the configuration of a network, from a file or from its inline
+    /// text.
+    pub async fn load(&self, source: ConfigSource<'_>) -> Result<Config, WgError> {
+        match source {
+            ConfigSource::File(name) => self.get_config(name).await,
+            ConfigSource::Inline(text) => parse_inline_config(text),
+        }
+    }
 }
 
 enum ConfigProviderInner {
     File { base_path: PathBuf },
 }
+
+/// Where the configuration of a network comes from.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ConfigSource<'a> {
+    /// A configuration file, by name.
+    File(&'a str),
+    /// Configuration text given in the network options.
+    Inline(&'a str),
+}
+
+impl<'a> ConfigSource<'a> {
+    /// The name of the configuration file, if the configuration comes from
+    /// one (and can be reloaded).
+    pub(crate) fn file_name(&self) -> Option<&'a str> {
+        match self {
+            ConfigSource::File(name) => Some(name),
+            ConfigSource::Inline(_) => None,
+        }
+    }
+}
+
+/// Decode a configuration given as base64 in `wireguard-config-inline`.
+pub(crate) fn decode_inline_config(encoded: &str) -> Result<String, WgError> {
+    use base64::prelude::*;
+    let bytes = BASE64_STANDARD
+        .decode(encoded.trim())
+        .map_err(|err| WgErrorInner::ConfigParse(format!("invalid base64: {err}")))?;
+    String::from_utf8(bytes)
+        .map_err(|_| WgErrorInner::ConfigParse("config is not valid UTF-8".to_string()).into())
+}
+
+/// Build the text of a configuration from individual `wireguard.*` network
+/// options, such as `wireguard.private-key` or `wireguard.peer.0.endpoint`.
+///
+/// Peers are written in the order of their index. The result is meant to be
+/// checked with the usual parser.
+pub(crate) fn config_from_options<'a>(
+    options: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<String, WgError> {
+    let mut interface = Vec::new();
+    let mut peers: BTreeMap<u32, Vec<(&str, &str)>> = BTreeMap::new();
+    for (option, value) in options {
+        let Some(name) = option.strip_prefix("wireguard.") else {
+            continue;
+        };
+        if value.contains(['\n', '\r']) {
+            return Err(WgErrorInner::ConfigParse(format!(
+                "option {option}: value should be a single line"
+            ))
+            .into());
+        }
+        let unknown = || WgErrorInner::ConfigParse(format!("unknown option {option}"));
+        if let Some(peer) = name.strip_prefix("peer.") {
+            let (index, name) = peer.split_once('.').ok_or_else(unknown)?;
+            let index: u32 = index.parse().map_err(|_| unknown())?;
+            let key = match name {
+                "public-key" => "PublicKey",
+                "preshared-key" => "PresharedKey",
+                "endpoint" => "Endpoint",
+                "allowed-ips" => "AllowedIPs",
+                "persistent-keepalive" => "PersistentKeepalive",
+                _ => return Err(unknown().into()),
+            };
+            peers.entry(index).or_default().push((key, value));
+        } else {
+            let key = match name {
+                "private-key" => "PrivateKey",
+                "listen-port" => "ListenPort",
+                "fwmark" => "FwMark",
+                "address" => "Address",
+                "dns" => "DNS",
+                "mtu" => "MTU",
+                "address-pool" => "AddressPool",
+                _ => return Err(unknown().into()),
+            };
+            interface.push((key, value));
+        }
+    }
+
+    let mut text = String::from("[Interface]\n");
+    for (key, value) in interface {
+        writeln!(text, "{key} = {value}").unwrap();
+    }
+    for properties in peers.values() {
+        text.push_str("\n[Peer]\n");
+        for (key, value) in properties {
+            writeln!(text, "{key} = {value}").unwrap();
+        }
+    }
+    Ok(text)
+}
+
+/// Watches a configuration directory for changes.
+pub(crate) struct ConfigWatcher {
+    events: inotify::EventStream<Vec<u8>>,
+}
+
+impl ConfigWatcher {
+    fn new(base_path: &std::path::Path) -> Result<Self, WgError> {
+        use inotify::{Inotify, WatchMask};
+        let inotify = Inotify::init().map_err(WgErrorInner::from)?;
+        // editors often write a temporary file and rename it
+        inotify
+            .watches()
+            .add(base_path, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+            .map_err(WgErrorInner::from)?;
+        let events = inotify
+            .into_event_stream(vec![0; 4096])
+            .map_err(WgErrorInner::from)?;
+        Ok(Self { events })
+    }
+
+    /// Wait for the next configuration to change, and return its name.
+    pub(crate) async fn next(&mut self) -> Option<String> {
+        use futures_util::stream::StreamExt;
+        loop {
+            let event = match self.events.next().await? {
+                Ok(event) => event,
+                Err(err) => {
+                    log::error!(err:display; "Error watching configuration directory");
+                    return None;
+                }
+            };
+            let Some(name) = event.name else {
+                continue;
+            };
+            let path = std::path::Path::new(&name);
+            if path.extension().is_some_and(|ext| ext == "conf") {
+                if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    return Some(stem.to_owned());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const PRIVATE_KEY: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const PUBLIC_KEY: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const OTHER_KEY: &str = "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=";
+
+    #[test]
+    fn test_parse_mtu() {
+        let text = format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\nMTU = 1380\n\n\
+             [Peer]\nPublicKey = {PUBLIC_KEY}\nAllowedIPs = 10.0.0.0/24\n"
+        );
+        let config = parse_config(&text).unwrap();
+        assert_eq!(config.mtu(), Some(1380));
+
+        let text = format!("[Interface]\nPrivateKey = {PRIVATE_KEY}\nMTU = big\n");
+        assert!(parse_config(&text).is_err());
+
+        for mtu in ["576", "65536"] {
+            let text = format!("[Interface]\nPrivateKey = {PRIVATE_KEY}\nMTU = {mtu}\n");
+            let (config, diagnostics) = parse_config_diagnostics(&text);
+            assert!(config.is_none());
+            assert_eq!(
+                diagnostics[0].message,
+                "MTU should be between 1280 and 65535"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        let text = format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\n\
+             Address = 10.0.0.2/32, fd00::2/128\n\
+             Address = 10.0.1.2/32\n"
+        );
+        let config = parse_config(&text).unwrap();
+        let addresses: Vec<_> = config.addresses().iter().map(|a| a.to_string()).collect();
+        assert_eq!(addresses, ["10.0.0.2/32", "fd00::2/128", "10.0.1.2/32"]);
+
+        let (ipv4, ipv6) = config.primary_addresses();
+        assert_eq!(ipv4.unwrap().to_string(), "10.0.0.2/32");
+        assert_eq!(ipv6.unwrap().to_string(), "fd00::2/128");
+        let secondary: Vec<_> = config
+            .secondary_addresses()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(secondary, ["10.0.1.2/32"]);
+    }
+
+    #[test]
+    fn test_parse_dns() {
+        let text = format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\n\
+             DNS = 10.0.0.1, fd00::1, internal.example\n"
+        );
+        let config = parse_config(&text).unwrap();
+        let servers: Vec<_> = config.dns_servers().iter().map(|a| a.to_string()).collect();
+        assert_eq!(servers, ["10.0.0.1", "fd00::1"]);
+        assert_eq!(config.dns_search(), ["internal.example"]);
+    }
+
+    #[test]
+    fn test_parse_key_indirection() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("wg-key-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_path = dir.join("private.key");
+        std::fs::write(&key_path, format!("{PRIVATE_KEY}\n")).unwrap();
+        let parse = |property: &str| {
+            parse_config(&format!(
+                "[Interface]\n{property}\n[Peer]\nPublicKey = {PUBLIC_KEY}\n"
+            ))
+        };
+
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let expected: Option<Key> = Some(PRIVATE_KEY.parse().unwrap());
+        let config = parse(&format!("PrivateKeyFile = {}", key_path.display())).unwrap();
+        assert_eq!(config.private_key, expected);
+        let config = parse(&format!("PrivateKey = file:{}", key_path.display())).unwrap();
+        assert_eq!(config.private_key, expected);
+
+        std::env::set_var("WG_KEY_TEST_PRIVATE_KEY", PRIVATE_KEY);
+        let config = parse("PrivateKey = env:WG_KEY_TEST_PRIVATE_KEY").unwrap();
+        assert_eq!(config.private_key, expected);
+        let key_error = |property: &str, kind: DiagnosticKind| {
+            let err = parse(property).unwrap_err();
+            let WgErrorInner::Diagnostics(Diagnostics(diagnostics)) = err.0 else {
+                panic!("unexpected error {err}");
+            };
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics[0].kind, kind);
+            assert_eq!(diagnostics[0].span.unwrap().line, 2);
+            diagnostics[0].message.clone()
+        };
+        assert_eq!(
+            key_error(
+                "PrivateKey = env:WG_KEY_TEST_UNSET",
+                DiagnosticKind::KeyEnvMissing
+            ),
+            WgErrorInner::KeyEnvMissing("WG_KEY_TEST_UNSET".to_owned()).to_string()
+        );
+
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        for property in [
+            format!("PrivateKeyFile = {}", key_path.display()),
+            format!("PrivateKey = file:{}", key_path.display()),
+        ] {
+            let message = key_error(&property, DiagnosticKind::KeyFilePermissions);
+            assert!(message.ends_with("(mode 644)"), "{message}");
+        }
+
+        let missing = dir.join("missing.key");
+        let message = key_error(
+            &format!("PrivateKeyFile = {}", missing.display()),
+            DiagnosticKind::KeyFileUnreadable,
+        );
+        assert!(message.starts_with("cannot read key file"), "{message}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_inline_key_indirection() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("wg-inline-key-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_path = dir.join("private.key");
+        std::fs::write(&key_path, format!("{PRIVATE_KEY}\n")).unwrap();
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::env::set_var("WG_INLINE_KEY_TEST_PSK", PRIVATE_KEY);
+        let parse = |interface: &str, peer: &str| {
+            parse_inline_config(&format!(
+                "[Interface]\n{interface}\n[Peer]\nPublicKey = {PUBLIC_KEY}\n{peer}\n"
+            ))
+        };
+
+        let config = parse(&format!("PrivateKey = {PRIVATE_KEY}"), "").unwrap();
+        assert_eq!(config.private_key, Some(PRIVATE_KEY.parse().unwrap()));
+
+        for (interface, peer) in [
+            (format!("PrivateKeyFile = {}", key_path.display()), ""),
+            (format!("PrivateKey = file:{}", key_path.display()), ""),
+            (
+                format!("PrivateKey = {PRIVATE_KEY}"),
+                "PresharedKey = env:WG_INLINE_KEY_TEST_PSK",
+            ),
+        ] {
+            let err = parse(&interface, peer).unwrap_err();
+            let WgErrorInner::Diagnostics(Diagnostics(diagnostics)) = err.0 else {
+                panic!("unexpected error {err}");
+            };
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics[0].kind, DiagnosticKind::KeyIndirection);
+        }
+
+        // configuration files can still refer to keys
+        let text = format!(
+            "[Interface]\nPrivateKeyFile = {}\n[Peer]\nPublicKey = {PUBLIC_KEY}\n",
+            key_path.display()
+        );
+        std::fs::set_permissions(
+            &key_path,
+            std::os::unix::fs::PermissionsExt::from_mode(0o600),
+        )
+        .unwrap();
+        assert!(parse_config(&text).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let text = format!(
+            "# comment\n\n[Interface]\nPrivateKey = {PRIVATE_KEY}\nMTU = big\n\
+             PostUp = iptables -A FORWARD -i %i -j ACCEPT\nMTU = 1420\n\n\
+             [Peer]\n# the hub\nAllowedIPs = 10.0.0.0/24, 10.1.0.0/xx\n\n\
+             [Extra]\nFoo = bar\n"
+        );
+        let (config, diagnostics) = parse_config_diagnostics(&text);
+        assert!(config.is_none());
+        let summary: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let span = diagnostic.span.unwrap();
+                (
+                    diagnostic.severity,
+                    diagnostic.kind,
+                    span.line,
+                    span.start,
+                    span.end,
+                    diagnostic.section,
+                    diagnostic.key.as_deref(),
+                )
+            })
+            .collect();
+        use DiagnosticKind::*;
+        use Severity::*;
+        assert_eq!(
+            summary,
+            [
+                (
+                    Error,
+                    InvalidValue,
+                    5,
+                    7,
+                    10,
+                    Some("Interface"),
+                    Some("MTU")
+                ),
+                (
+                    Warning,
+                    IgnoredKey,
+                    6,
+                    1,
+                    7,
+                    Some("Interface"),
+                    Some("PostUp")
+                ),
+                (
+                    Warning,
+                    DuplicateKey,
+                    7,
+                    1,
+                    4,
+                    Some("Interface"),
+                    Some("MTU")
+                ),
+                (
+                    Error,
+                    InvalidValue,
+                    11,
+                    27,
+                    38,
+                    Some("Peer"),
+                    Some("AllowedIPs")
+                ),
+                (Error, MissingKey, 9, 2, 6, Some("Peer"), None),
+                (Error, UnknownSection, 13, 2, 7, None, None),
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error: line 5, column 7: MTU should be a valid integer"
+        );
+    }
+
+    #[test]
+    fn test_check_config() {
+        let text = format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\nAddress = 192.168.1.2/24, 10.0.0.2/24\n\n\
+             [Peer]\nPublicKey = {OTHER_KEY}\nEndpoint = 192.0.2.1:51820\nAllowedIPs = 10.0.0.0/24\n\n\
+             [Peer]\nPublicKey = {OTHER_KEY}\nAllowedIPs = 10.0.0.0/24, 10.0.0.128/25\n"
+        );
+        let summary: Vec<_> = check_config(&text)
+            .iter()
+            .map(|diagnostic| {
+                let span = diagnostic.span.unwrap();
+                (diagnostic.severity, diagnostic.kind, span.line, span.start)
+            })
+            .collect();
+        use DiagnosticKind::*;
+        use Severity::*;
+        assert_eq!(
+            summary,
+            [
+                (Error, DuplicatePublicKey, 11, 13),
+                (Error, OverlappingAllowedIps, 12, 14),
+                (Warning, OverlappingAllowedIps, 12, 27),
+                (Error, UnreachablePeer, 10, 2),
+                (Warning, UnroutedAddress, 3, 11),
+            ]
+        );
+
+        let text = format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\nListenPort = 51820\nAddress = 10.0.0.1/24\n\n\
+             [Peer]\nPublicKey = {OTHER_KEY}\nAllowedIPs = 10.0.0.2/32\n"
+        );
+        assert_eq!(check_config(&text), []);
+
+        // only parse errors are reported for invalid configurations
+        let text = "[Interface]\nAddress = 192.168.1.2/24\n\n[Peer]\nAllowedIPs = 10.0.0.0/24\n";
+        assert_eq!(check_config(text), parse_config_diagnostics(text).1);
+    }
+
+    #[test]
+    fn test_config_from_options() {
+        let options = [
+            ("wireguard.peer.1.public-key", OTHER_KEY),
+            ("wireguard.private-key", PRIVATE_KEY),
+            ("wireguard.address", "10.0.0.2/24"),
+            ("wireguard.peer.0.public-key", PUBLIC_KEY),
+            ("wireguard.peer.0.allowed-ips", "10.0.0.0/24"),
+            ("wireguard-dns", "override"),
+        ];
+        let text = config_from_options(options).unwrap();
+        let config = parse_config(&text).unwrap();
+        assert_eq!(config.addresses().len(), 1);
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.peers[0].public_key, PUBLIC_KEY.parse().unwrap());
+        assert_eq!(config.peers[0].allowed_ips.len(), 1);
+        assert_eq!(config.peers[1].public_key, OTHER_KEY.parse().unwrap());
+
+        assert!(config_from_options([("wireguard.peer.x.public-key", PUBLIC_KEY)]).is_err());
+        assert!(config_from_options([("wireguard.table", "off")]).is_err());
+        assert!(config_from_options([("wireguard.mtu", "1420\n[Peer]")]).is_err());
+    }
+
+    struct StubResolver(std::sync::Mutex<Vec<SocketAddr>>);
+
+    impl Resolver for StubResolver {
+        fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+            assert_eq!((host, port), ("vpn.example.com", 51820));
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    #[test]
+    fn test_resolve_endpoints() {
+        let text = format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\n\
+            [Peer]\nPublicKey = {PUBLIC_KEY}\nEndpoint = vpn.example.com:51820\n"
+        );
+        let mut config = parse_config(&text).unwrap();
+        assert!(config.has_hostname_endpoints());
+        let endpoint = |config: &Config| config.peers[0].endpoint.as_ref().unwrap().addr().copied();
+        assert_eq!(endpoint(&config), None);
+
+        let a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let resolver = StubResolver(vec![a].into());
+        config.resolve_endpoints(&resolver).unwrap();
+        assert_eq!(endpoint(&config), Some(a));
+
+        *resolver.0.lock().unwrap() = vec![b, a];
+        assert!(!config.reresolve_endpoints(&resolver));
+        assert_eq!(endpoint(&config), Some(a));
+
+        *resolver.0.lock().unwrap() = vec![b];
+        assert!(config.reresolve_endpoints(&resolver));
+        assert_eq!(endpoint(&config), Some(b));
+
+        // keep the last known address when resolution fails
+        resolver.0.lock().unwrap().clear();
+        assert!(!config.reresolve_endpoints(&resolver));
+        assert_eq!(endpoint(&config), Some(b));
+
+        let mut config = parse_config(&text).unwrap();
+        assert!(config.resolve_endpoints(&resolver).is_err());
+
+        let text = text.replace("vpn.example.com:51820", "vpn example:51820");
+        assert!(parse_config(&text).is_err());
+    }
+
+    #[test]
+    fn test_key() {
+        let private_key: Key = PRIVATE_KEY.parse().unwrap();
+        assert_eq!(private_key.to_string(), PRIVATE_KEY);
+        assert_eq!(private_key.public_key().to_string(), OTHER_KEY);
+        assert_eq!(format!("{private_key:?}"), "Key(<redacted>)");
+
+        // too short, or too long
+        assert!("AAAA".parse::<Key>().is_err());
+        assert!(format!("{PRIVATE_KEY}AAAA").parse::<Key>().is_err());
+
+        let generated = Key::generate();
+        assert_ne!(generated, Key::generate());
+        assert_eq!(generated.bytes()[0] & 7, 0);
+        assert_eq!(generated.bytes()[31] & 0xc0, 0x40);
+        assert_eq!(generated.to_string().parse::<Key>().unwrap(), generated);
+
+        let json = serde_json::to_string(&private_key).unwrap();
+        assert_eq!(json, format!("\"{PRIVATE_KEY}\""));
+        assert_eq!(serde_json::from_str::<Key>(&json).unwrap(), private_key);
+        let err = serde_json::from_str::<Key>("\"AAAA\"").unwrap_err();
+        assert!(!err.to_string().contains("AAAA"), "{err}");
+    }
+
+    #[test]
+    fn test_export_peer() {
+        let text = format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\nAddress = 10.0.0.2/24, fd00::2/64\n\n\
+             [Peer]\nPublicKey = {PUBLIC_KEY}\nPresharedKey = {OTHER_KEY}\nAllowedIPs = 10.0.0.0/24\n"
+        );
+        let config = parse_config(&text).unwrap();
+        let public_key = PRIVATE_KEY.parse::<Key>().unwrap().public_key();
+        assert_eq!(
+            config.export_peer("mynet").unwrap(),
+            format!(
+                "# mynet\n[Peer]\nPublicKey = {public_key}\nPresharedKey = {OTHER_KEY}\n\
+                 AllowedIPs = 10.0.0.2/32, fd00::2/128\n"
+            )
+        );
+
+        let text = "[Interface]\nAddressPool = 10.0.0.0/24\n";
+        assert_eq!(parse_config(text).unwrap().export_peer("mynet"), None);
+    }
+
+    #[test]
+    fn test_digest() {
+        let digest = |private_key: &str, preshared_key: Option<&str>| {
+            let mut text = format!(
+                "[Interface]\nPrivateKey = {private_key}\nAddress = 10.0.0.2/24\n\n\
+                 [Peer]\nPublicKey = {PUBLIC_KEY}\nAllowedIPs = 10.0.0.0/24\n"
+            );
+            if let Some(preshared_key) = preshared_key {
+                writeln!(text, "PresharedKey = {preshared_key}").unwrap();
+            }
+            parse_config(&text).unwrap().digest()
+        };
+        let base = digest(PRIVATE_KEY, Some(OTHER_KEY));
+        assert_eq!(base.len(), 64);
+        assert_eq!(base, digest(PRIVATE_KEY, Some(OTHER_KEY)));
+        assert_ne!(base, digest(OTHER_KEY, Some(OTHER_KEY)));
+        assert_ne!(base, digest(PRIVATE_KEY, None));
+        // changing only the preshared key shows
+        assert_ne!(base, digest(PRIVATE_KEY, Some(PUBLIC_KEY)));
+    }
+
+    #[test]
+    fn test_address_pool() {
+        let text = format!(
+            "[Interface]\nAddressPool = 10.0.0.0/30\n\n\
+             [Peer]\nPublicKey = {PUBLIC_KEY}\nAllowedIPs = 10.0.0.0/24\n"
+        );
+        let mut config = parse_config(&text).unwrap();
+        assert_eq!(config.private_key, None);
+        let pool = config.address_pool().unwrap().clone();
+
+        let first = pool.allocate(&[]).unwrap();
+        assert_eq!(first.to_string(), "10.0.0.1/30");
+        let second = pool.allocate(&[*first.ip()]).unwrap();
+        assert_eq!(second.to_string(), "10.0.0.2/30");
+        assert_eq!(pool.allocate(&[*first.ip(), *second.ip()]), None);
+
+        let pool: CidrAddress = "fd00::/127".parse().unwrap();
+        assert_eq!(pool.allocate(&[]).unwrap().to_string(), "fd00::1/127");
+
+        let key = Key::generate();
+        config.set_identity(key.clone(), vec![first]);
+        assert_eq!(config.private_key, Some(key));
+        assert_eq!(
+            config.primary_addresses().0.unwrap().to_string(),
+            "10.0.0.1/30"
+        );
+
+        let text = format!("[Interface]\nPrivateKey = {PRIVATE_KEY}\nAddressPool = 10.0.0.0/24\n");
+        assert!(parse_config(&text).is_err());
+        let text = "[Interface]\nAddress = 10.0.0.1/24\nAddressPool = 10.0.0.0/24\n";
+        assert!(parse_config(text).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let text = format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\nListenPort = 51820\n\
+             Address = 10.0.0.2/24,fd00::2/64\nDNS = 10.0.0.1,example.com\n\n\
+             [Peer]\nPublicKey = {PUBLIC_KEY}\nEndpoint = vpn.example.com:51820\n\
+             AllowedIPs = 0.0.0.0/0,::/0\nPersistentKeepalive = 25\n"
+        );
+        let config = parse_config(&text).unwrap();
+        assert_eq!(
+            config.to_string(),
+            format!(
+                "[Interface]\nPrivateKey = {PRIVATE_KEY}\nListenPort = 51820\n\
+                 Address = 10.0.0.2/24, fd00::2/64\nDNS = 10.0.0.1, example.com\n\n\
+                 [Peer]\nPublicKey = {PUBLIC_KEY}\nEndpoint = vpn.example.com:51820\n\
+                 AllowedIPs = 0.0.0.0/0, ::/0\nPersistentKeepalive = 25\n"
+            )
+        );
+    }
+
+    fn key() -> impl Strategy<Value = Key> {
+        any::<[u8; 32]>().prop_map(Key::from)
+    }
+
+    fn ip() -> impl Strategy<Value = IpAddr> {
+        prop_oneof![
+            any::<Ipv4Addr>().prop_map(IpAddr::V4),
+            any::<Ipv6Addr>().prop_map(IpAddr::V6),
+        ]
+    }
+
+    fn cidr_address() -> impl Strategy<Value = CidrAddress> {
+        ip().prop_flat_map(|ip| {
+            let bits: u8 = if ip.is_ipv4() { 32 } else { 128 };
+            (0..=bits).prop_map(move |cidr| CidrAddress { ip, cidr })
+        })
+    }
+
+    const HOST_NAME: &str = "[a-z][a-z0-9-]{0,10}(\\.[a-z][a-z0-9-]{0,10}){0,3}";
+
+    fn peer() -> impl Strategy<Value = Peer> {
+        let endpoint = prop_oneof![
+            (ip(), any::<u16>())
+                .prop_map(|(ip, port)| PeerEndpoint::Addr(SocketAddr::new(ip, port))),
+            (HOST_NAME, any::<u16>()).prop_map(|(host, port)| PeerEndpoint::Host {
+                host,
+                port,
+                resolved: None,
+            }),
+        ];
+        (
+            key(),
+            proptest::option::of(key()),
+            proptest::option::of(endpoint),
+            proptest::collection::vec(cidr_address(), 0..4),
+            proptest::option::of(1..=u16::MAX),
+        )
+            .prop_map(
+                |(public_key, preshared_key, endpoint, allowed_ips, persistent_keepalive)| Peer {
+                    public_key,
+                    preshared_key,
+                    endpoint,
+                    allowed_ips,
+                    persistent_keepalive: persistent_keepalive.and_then(NonZeroU16::new),
+                },
+            )
+    }
+
+    fn config() -> impl Strategy<Value = Config> {
+        // either a private key with addresses, or an address pool
+        let identity = prop_oneof![
+            (key(), proptest::collection::vec(cidr_address(), 0..4))
+                .prop_map(|(key, addresses)| (Some(key), addresses, None)),
+            cidr_address().prop_map(|pool| (None, Vec::new(), Some(pool))),
+        ];
+        (
+            identity,
+            proptest::option::of(any::<u16>()),
+            proptest::option::of(any::<u32>()),
+            proptest::option::of(MTU_RANGE),
+            proptest::collection::vec(ip(), 0..3),
+            proptest::collection::vec(HOST_NAME, 0..3),
+            proptest::collection::vec(peer(), 0..4),
+        )
+            .prop_map(
+                |(
+                    (private_key, addresses, address_pool),
+                    listen_port,
+                    fw_mark,
+                    mtu,
+                    dns_servers,
+                    dns_search,
+                    peers,
+                )| Config {
+                    private_key,
+                    listen_port,
+                    fw_mark,
+                    addresses,
+                    mtu,
+                    dns_servers,
+                    dns_search,
+                    peers,
+                    address_pool,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn test_display_roundtrip(config in config()) {
+            let text = config.to_string();
+            prop_assert_eq!(parse_config(&text).unwrap(), config, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = parse_config(&format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\nListenPort = 51820\n\
+             [Peer]\nPublicKey = {PUBLIC_KEY}\nEndpoint = 192.0.2.1:51820\n\
+             [Peer]\nPublicKey = {OTHER_KEY}\nAllowedIPs = 10.0.0.0/24\n"
+        ))
+        .unwrap();
+        assert!(old.diff(&old).is_empty());
+
+        let new = parse_config(&format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\nListenPort = 51820\nMTU = 1380\n\
+             [Peer]\nPublicKey = {PUBLIC_KEY}\nEndpoint = 192.0.2.2:51820\n"
+        ))
+        .unwrap();
+        let diff = old.diff(&new);
+        assert!(diff.private_key.is_none());
+        assert!(diff.listen_port.is_none());
+        assert_eq!(diff.removed_peers, [OTHER_KEY.parse().unwrap()]);
+        assert_eq!(diff.changed_peers, [&new.peers[0]]);
+    }
+}
@@ -1,50 +1,231 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use futures_util::stream::StreamExt;
+use futures_util::stream::{StreamExt, TryStreamExt};
 use rtnetlink::{
     new_connection,
-    packet_core::NetlinkMessage,
+    packet_core::{NetlinkMessage, NetlinkPayload, NLM_F_DUMP, NLM_F_REQUEST},
     packet_route::{
-        link::{LinkAttribute, LinkMessage},
-        RouteNetlinkMessage,
+        link::{InfoKind, LinkAttribute, LinkInfo, LinkMessage},
+        route::{RouteAddress, RouteAttribute, RouteMessage, RouteMetric, RouteType},
+        AddressFamily, RouteNetlinkMessage,
     },
     LinkWireguard,
 };
 use thiserror::Error;
 use tokio::sync::Mutex as AsyncMutex;
 use tokio::task::JoinHandle;
-use wireguard_uapi::WgSocket;
+use wireguard_uapi::{DeviceInterface, WgSocket};
 
 use crate::api::EndpointId;
+use crate::db::{Db, Endpoint};
 
-use super::{Config, WgError};
+use super::{
+    Config, ConfigDiff, Key, Peer, PeerEndpoint, Resolver, SystemResolver, WgError, MTU_RANGE,
+};
 
 #[derive(Debug, Error)]
 pub(super) enum WgErrorInner {
     #[error("rtnetlink error: {0}")]
     RequestFailed(#[from] rtnetlink::Error),
-    #[error("I/O error")]
+    #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
     #[error("WireGuard connection error")]
     WgSocket(#[from] wireguard_uapi::err::ConnectError),
     #[error("error reading config: {0}")]
     ConfigParse(String),
+    #[error("{0}")]
+    Diagnostics(super::Diagnostics),
+    #[error("cannot read key file {}: {1}", .0.display())]
+    KeyFile(PathBuf, #[source] std::io::Error),
+    #[error("key file {} should not be readable by group or others (mode {1:o})", .0.display())]
+    KeyFilePermissions(PathBuf, u32),
+    #[error("environment variable {0} for key is not set")]
+    KeyEnvMissing(String),
+    #[error("cannot resolve endpoint {0}: {1}")]
+    Resolve(String, #[source] std::io::Error),
     #[error("WireGuard device configuration error: {0}")]
     SetDevice(#[from] wireguard_uapi::err::SetDeviceError),
+    #[error("WireGuard device query error: {0}")]
+    GetDevice(#[from] wireguard_uapi::err::GetDeviceError),
     #[error("aborted")]
     Aborted(#[from] tokio::task::JoinError),
 }
 
+/// A WireGuard interface created by the plugin.
+pub(crate) struct Interface {
+    name: String,
+    index: u32,
+}
+
+impl Interface {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The interface index in the namespace where it was created. The kernel
+    /// keeps it when the link is moved to a namespace where it's not taken.
+    pub(crate) fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+/// An interface that is currently in use by an endpoint.
+struct LiveInterface {
+    name: String,
+    /// The configuration file, if there is one to reload from.
+    config_name: Option<String>,
+    config: Config,
+    /// Sandbox path and interface index, once Docker has moved the link.
+    sandbox: Option<(PathBuf, u32)>,
+    created: SystemTime,
+    /// Peers without a recent handshake, as found by the watchdog.
+    stale_peers: Vec<Key>,
+}
+
+impl LiveInterface {
+    /// Run `f` with a WireGuard socket in the namespace where the interface
+    /// currently lives. The socket is only bound to the right namespace,
+    /// the interface has to be selected with [`Self::uapi_device`].
+    fn with_socket<T>(
+        &self,
+        host_socket: &Mutex<WgSocket>,
+        f: impl FnOnce(&mut WgSocket) -> T,
+    ) -> Result<T, WgErrorInner> {
+        with_sandbox_socket(self.sandbox.as_ref(), host_socket, f)
+    }
+
+    /// The interface of an endpoint that was joined to a sandbox before the
+    /// plugin was restarted, if it has one. Until Docker has moved the link,
+    /// which is when its index is recorded, it is looked up by name here.
+    fn restored(endpoint: &Endpoint, config_name: Option<&str>, config: Config) -> Option<Self> {
+        let name = endpoint.interface()?.to_owned();
+        let sandbox_key = endpoint.sandbox_key()?;
+        Some(Self {
+            name,
+            config_name: config_name.map(ToOwned::to_owned),
+            config,
+            sandbox: endpoint
+                .sandbox_index()
+                .map(|index| (sandbox_key.to_owned(), index)),
+            created: SystemTime::now(),
+            stale_peers: Vec::new(),
+        })
+    }
+
+    fn device_interface(&self) -> DeviceInterface<'_> {
+        sandbox_device_interface(&self.name, self.sandbox.as_ref())
+    }
+
+    fn stats_query(&self, endpoint_id: &str) -> StatsQuery {
+        StatsQuery {
+            endpoint_id: endpoint_id.to_owned(),
+            name: self.name.clone(),
+            config_name: self.config_name.clone(),
+            sandbox: self.sandbox.clone(),
+            stale_peers: self.stale_peers.clone(),
+        }
+    }
+
+    fn uapi_device(&self) -> wireguard_uapi::set::Device<'_> {
+        match &self.sandbox {
+            None => wireguard_uapi::set::Device::from_ifname(&self.name),
+            Some((_, index)) => wireguard_uapi::set::Device::from_ifindex(*index),
+        }
+    }
+}
+
+/// What is needed to read the statistics of a live interface, copied so
+/// that the lock on the live interfaces is not held while the kernel is
+/// queried.
+struct StatsQuery {
+    endpoint_id: String,
+    name: String,
+    config_name: Option<String>,
+    sandbox: Option<(PathBuf, u32)>,
+    stale_peers: Vec<Key>,
+}
+
+/// Run `f` with a WireGuard socket in the namespace where an interface
+/// currently lives: the sandbox, or ours if Docker has not moved the link.
+fn with_sandbox_socket<T>(
+    sandbox: Option<&(PathBuf, u32)>,
+    host_socket: &Mutex<WgSocket>,
+    f: impl FnOnce(&mut WgSocket) -> T,
+) -> Result<T, WgErrorInner> {
+    match sandbox {
+        None => Ok(f(&mut host_socket.lock().unwrap())),
+        Some((sandbox_key, _)) => {
+            let mut socket = crate::netns::run_in_namespace(sandbox_key, || {
+                WgSocket::connect().map_err(std::io::Error::other)
+            })?;
+            Ok(f(&mut socket))
+        }
+    }
+}
+
+fn sandbox_device_interface<'a>(
+    name: &'a str,
+    sandbox: Option<&(PathBuf, u32)>,
+) -> DeviceInterface<'a> {
+    match sandbox {
+        None => DeviceInterface::from_name(name),
+        Some((_, index)) => DeviceInterface::from_index(*index),
+    }
+}
+
+/// Statistics of a live interface, as reported by the kernel.
+pub(crate) struct InterfaceStats {
+    pub(crate) endpoint_id: String,
+    pub(crate) if_name: String,
+    pub(crate) config_name: Option<String>,
+    pub(crate) public_key: Option<Key>,
+    pub(crate) listen_port: u16,
+    pub(crate) peers: Vec<PeerStats>,
+}
+
+pub(crate) struct PeerStats {
+    pub(crate) public_key: Key,
+    pub(crate) endpoint: Option<SocketAddr>,
+    pub(crate) rx_bytes: u64,
+    pub(crate) tx_bytes: u64,
+    /// `None` if there was no handshake yet.
+    pub(crate) last_handshake: Option<SystemTime>,
+    /// Whether the watchdog found that the handshake is overdue.
+    pub(crate) stale: bool,
+}
+
+/// Settings of the handshake watchdog.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct WatchdogSettings {
+    /// How long a peer can go without a handshake. By default, three times
+    /// its persistent keepalive interval, or [`DEFAULT_HANDSHAKE_TIMEOUT`].
+    pub(crate) timeout: Option<Duration>,
+    /// Set the endpoints of stale peers again, resolving hostnames.
+    pub(crate) recover: bool,
+}
+
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(180);
+
 pub(crate) struct Wg {
     #[expect(unused)]
     rt_task: JoinHandle<()>,
     rt: rtnetlink::Handle,
     wg_socket: Arc<Mutex<WgSocket>>,
     watcher: LinkWatcher,
+    /// Live interfaces by endpoint id.
+    interfaces: AsyncMutex<HashMap<String, LiveInterface>>,
+    resolver: Arc<dyn Resolver>,
+    /// Prefix of the names of our interfaces, to tell them apart from others.
+    interface_prefix: String,
 }
 
 impl Wg {
-    pub(crate) fn new() -> Result<Self, WgError> {
+    pub(crate) fn new(db: Arc<Db>, interface_prefix: String) -> Result<Self, WgError> {
         let (rt_connection, rt, _) = new_connection().map_err(WgErrorInner::from)?;
         let wg_socket = Arc::new(Mutex::new(WgSocket::connect().map_err(WgErrorInner::from)?));
         let rt_task = tokio::spawn(rt_connection);
@@ -52,40 +233,225 @@ impl Wg {
             rt_task,
             rt,
             wg_socket,
-            watcher: LinkWatcher::new()?,
+            watcher: LinkWatcher::new(db)?,
+            interfaces: Default::default(),
+            resolver: Arc::new(SystemResolver),
+            interface_prefix,
         })
     }
 
+    /// Save the state that is only kept in memory, before shutting down.
+    pub(crate) async fn persist(&self) {
+        self.watcher.persist().await;
+    }
+
+    /// Clean up interfaces left behind by a previous run of the plugin.
+    ///
+    /// Interfaces in our namespace that don't belong to a known endpoint are
+    /// deleted. Interfaces that were marked for deletion are deleted if they
+    /// are here, otherwise they stay marked until they show up.
+    pub(crate) async fn reconcile(&self) -> Result<(), WgError> {
+        let db = self.watcher.db.clone();
+        let (known, pending) = tokio::task::block_in_place(|| {
+            let known: Vec<_> = db
+                .list_endpoints()?
+                .into_iter()
+                .filter_map(|(_, endpoint)| endpoint.interface().map(ToOwned::to_owned))
+                .collect();
+            Ok::<_, std::io::Error>((known, db.pending_deletions()?))
+        })
+        .map_err(WgErrorInner::from)?;
+
+        let mut orphans = Vec::new();
+        let mut links = self.rt.link().get().execute();
+        while let Some(link) = links.try_next().await.map_err(WgErrorInner::from)? {
+            let Some(name) = get_name_from_link(&link) else {
+                continue;
+            };
+            // the prefix alone could match interfaces the plugin doesn't own
+            if is_interface_name(&self.interface_prefix, name)
+                && is_wireguard_link(&link)
+                && (!known.contains(name) || pending.contains(name))
+            {
+                orphans.push(name.clone());
+            }
+        }
+
+        for name in &orphans {
+            log::info!(if_name = name.as_str(); "Deleting orphaned interface");
+            if let Err(err) = delete_link_if_found(self.rt.clone(), name.clone()).await {
+                log::error!(err:display, if_name = name.as_str(); "Failed to delete interface");
+            }
+        }
+
+        for name in pending {
+            if !orphans.contains(&name) {
+                self.watcher.mark_for_deletion(name).await;
+            }
+        }
+        self.watcher.persist().await;
+        Ok(())
+    }
+
+    /// Create and configure the interface for an endpoint.
+    ///
+    /// If anything fails after the link is added, the link is deleted again.
+    /// An interface left over by a previous attempt for the same endpoint is
+    /// replaced, so that a retried Join does not fail.
     pub(crate) async fn create_interface(
         &self,
         endpoint_id: EndpointId<'_>,
+        config_name: Option<&str>,
         config: Config,
-    ) -> Result<String, WgError> {
-        let if_name = Self::interface_name(endpoint_id);
-        self.rt
-            .link()
-            .add(LinkWireguard::new(&if_name).build())
-            .execute()
+    ) -> Result<Interface, WgError> {
+        let if_name = self.interface_name(endpoint_id);
+        let config = self.resolve_endpoints(config).await?;
+        let mtu = match config.mtu {
+            Some(mtu) => mtu,
+            None => self.auto_mtu(&config).await,
+        };
+        // otherwise the watcher would delete the new link right away
+        self.watcher.unmark(&if_name).await;
+
+        log::debug!(if_name = if_name.as_str(), mtu; "Creating WireGuard interface");
+        let add_link = || {
+            self.rt
+                .link()
+                .add(LinkWireguard::new(&if_name).mtu(mtu).build())
+                .execute()
+        };
+        match add_link().await {
+            Err(err) if is_errno(&err, rustix::io::Errno::EXIST) => {
+                log::info!(if_name = if_name.as_str(); "Replacing existing interface");
+                delete_link_if_found(self.rt.clone(), if_name.clone())
+                    .await
+                    .map_err(WgErrorInner::from)?;
+                add_link().await.map_err(WgErrorInner::from)?;
+            }
+            result => result.map_err(WgErrorInner::from)?,
+        }
+
+        match self.configure_interface(&if_name, config).await {
+            Ok((index, config)) => {
+                self.interfaces.lock().await.insert(
+                    endpoint_id.to_string(),
+                    LiveInterface {
+                        name: if_name.clone(),
+                        config_name: config_name.map(ToOwned::to_owned),
+                        config,
+                        sandbox: None,
+                        created: SystemTime::now(),
+                        stale_peers: Vec::new(),
+                    },
+                );
+                Ok(Interface {
+                    name: if_name,
+                    index,
+                })
+            }
+            Err(err) => {
+                log::debug!(if_name = if_name.as_str(); "Rolling back interface creation");
+                if let Err(err) = delete_link_if_found(self.rt.clone(), if_name.clone()).await {
+                    log::error!(
+                        err:display,
+                        if_name = if_name.as_str();
+                        "Failed to delete interface"
+                    );
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Take back the interface of an endpoint that was joined to a sandbox
+    /// when the plugin was restarted, so that it is reloaded, watched and
+    /// reported again. The current version of the configuration is applied
+    /// in full, since it may have changed in the meantime.
+    pub(crate) async fn restore_interface(
+        &self,
+        endpoint_id: &str,
+        endpoint: &Endpoint,
+        config_name: Option<&str>,
+        config: Config,
+    ) -> Result<(), WgError> {
+        let config = self.resolve_endpoints(config).await?;
+        let Some(interface) = LiveInterface::restored(endpoint, config_name, config) else {
+            return Ok(());
+        };
+        tokio::task::block_in_place(|| {
+            interface.with_socket(&self.wg_socket, |wg_socket| {
+                let device = wg_socket
+                    .get_device(interface.device_interface())
+                    .map_err(WgErrorInner::from)?;
+                let removed_peers: Vec<Key> = device
+                    .peers
+                    .iter()
+                    .map(|peer| Key::from(peer.public_key))
+                    .filter(|key| {
+                        !interface
+                            .config
+                            .peers
+                            .iter()
+                            .any(|peer| peer.public_key == *key)
+                    })
+                    .collect();
+                let device =
+                    replace_uapi_device(interface.uapi_device(), &interface.config, &removed_peers);
+                wg_socket.set_device(device).map_err(WgErrorInner::from)
+            })?
+        })?;
+        log::info!(
+            endpoint_id,
+            if_name = interface.name.as_str();
+            "Restored interface"
+        );
+        let db = &self.watcher.db;
+        let result = tokio::task::block_in_place(|| {
+            db.update_endpoint(EndpointId::new(endpoint_id), |endpoint| {
+                endpoint.set_config_hash(interface.config.digest())
+            })
+        });
+        if let Err(err) = result {
+            log::error!(err:display, endpoint_id; "Failed to update endpoint");
+        }
+        self.interfaces
+            .lock()
+            .await
+            .insert(endpoint_id.to_owned(), interface);
+        Ok(())
+    }
+
+    /// Apply the WireGuard configuration to a new link, and return its index.
+    async fn configure_interface(
+        &self,
+        if_name: &str,
+        config: Config,
+    ) -> Result<(u32, Config), WgError> {
+        let index = link_index(self.rt.clone(), if_name.to_owned())
             .await
             .map_err(WgErrorInner::from)?;
 
-        {
+        let config = {
             let wg_socket = self.wg_socket.clone();
-            let if_name = if_name.clone();
+            let if_name = if_name.to_owned();
             tokio::task::spawn_blocking(move || {
                 let mut wg_socket = wg_socket.lock().unwrap();
                 let uapi_device = config_to_uapi_device(&if_name, &config);
-                wg_socket.set_device(uapi_device)
+                wg_socket.set_device(uapi_device).map(|()| config)
             })
             .await
             .map_err(WgErrorInner::from)?
-            .map_err(WgErrorInner::from)?;
-        }
-        Ok(if_name)
+            .map_err(WgErrorInner::from)?
+        };
+        Ok((index, config))
     }
 
     pub(crate) async fn delete_interface(&self, endpoint_id: EndpointId<'_>) {
-        let name = Self::interface_name(endpoint_id);
+        self.interfaces
+            .lock()
+            .await
+            .remove(&endpoint_id.to_string());
+        let name = self.interface_name(endpoint_id);
         if !delete_link_if_found(self.rt.clone(), name.clone())
             .await
             .unwrap_or(false)
@@ -94,9 +460,555 @@ impl Wg {
         }
     }
 
-    fn interface_name(endpoint_id: EndpointId<'_>) -> String {
+    /// Record that Docker has moved the interface of an endpoint into its
+    /// sandbox, where it has a different index.
+    pub(crate) async fn attach_sandbox(&self, endpoint_id: &str, sandbox_key: PathBuf, index: u32) {
+        if let Some(interface) = self.interfaces.lock().await.get_mut(endpoint_id) {
+            interface.sandbox = Some((sandbox_key, index));
+        }
+    }
+
+    /// Apply a new version of a configuration to every live interface that
+    /// was created from it. Only the settings that changed are sent to the
+    /// kernel, so that sessions with unchanged peers are kept.
+    pub(crate) async fn reload(&self, config_name: &str, config: Config) {
+        let config = match self.resolve_endpoints(config).await {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!(err:display, config_name; "Not reloading configuration");
+                return;
+            }
+        };
+        let mut interfaces = self.interfaces.lock().await;
+        for (endpoint_id, interface) in interfaces.iter_mut() {
+            if interface.config_name.as_deref() != Some(config_name) {
+                continue;
+            }
+            let mut config = config.clone();
+            if config.address_pool.is_some() {
+                // the endpoint keeps its generated key and allocated address
+                config.private_key.clone_from(&interface.config.private_key);
+                config.addresses.clone_from(&interface.config.addresses);
+            }
+            match self.apply_config(interface, config.clone()) {
+                Ok(false) => {}
+                Ok(true) => {
+                    log::info!(
+                        endpoint_id = endpoint_id.as_str(),
+                        if_name = interface.name.as_str(),
+                        config_name;
+                        "Reloaded configuration"
+                    );
+                    let db = &self.watcher.db;
+                    let endpoint_id = EndpointId::new(endpoint_id);
+                    let result = tokio::task::block_in_place(|| {
+                        db.update_endpoint(endpoint_id, |endpoint| {
+                            endpoint.set_config_hash(config.digest())
+                        })
+                    });
+                    if let Err(err) = result {
+                        log::error!(err:display, endpoint_id:display; "Failed to update endpoint");
+                    }
+                }
+                Err(err) => log::error!(
+                    err:display,
+                    endpoint_id = endpoint_id.as_str();
+                    "Failed to reload configuration"
+                ),
+            }
+        }
+    }
+
+    /// Resolve the hostnames of peer endpoints again, like wireguard-tools'
+    /// `reresolve-dns.sh`, and update the peers whose address changed.
+    pub(crate) async fn reresolve_endpoints(&self) {
+        let configs: Vec<_> = self
+            .interfaces
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, interface)| interface.config.has_hostname_endpoints())
+            .map(|(endpoint_id, interface)| (endpoint_id.clone(), interface.config.clone()))
+            .collect();
+        if configs.is_empty() {
+            return;
+        }
+
+        // resolve without holding the lock, since it can take a while
+        let resolver = self.resolver.clone();
+        let changed = tokio::task::spawn_blocking(move || {
+            configs
+                .into_iter()
+                .filter_map(|(endpoint_id, old)| {
+                    let mut new = old.clone();
+                    new.reresolve_endpoints(&*resolver)
+                        .then_some((endpoint_id, old, new))
+                })
+                .collect::<Vec<_>>()
+        })
+        .await;
+        let Ok(changed) = changed else {
+            return;
+        };
+
+        let mut interfaces = self.interfaces.lock().await;
+        for (endpoint_id, old, new) in changed {
+            let Some(interface) = interfaces.get_mut(&endpoint_id) else {
+                continue;
+            };
+            // reloaded in the meantime
+            if interface.config != old {
+                continue;
+            }
+            log::info!(
+                endpoint_id = endpoint_id.as_str(),
+                if_name = interface.name.as_str();
+                "Updating peer endpoint addresses"
+            );
+            if let Err(err) = self.apply_config(interface, new) {
+                log::error!(
+                    err:display,
+                    endpoint_id = endpoint_id.as_str();
+                    "Failed to update peer endpoints"
+                );
+            }
+        }
+    }
+
+    /// Send the differences between the current and the new configuration of
+    /// a live interface to the kernel. Returns whether anything changed.
+    fn apply_config(&self, interface: &mut LiveInterface, config: Config) -> Result<bool, WgError> {
+        let diff = interface.config.diff(&config);
+        if diff.is_empty() {
+            return Ok(false);
+        }
+        tokio::task::block_in_place(|| {
+            interface
+                .with_socket(&self.wg_socket, |wg_socket| {
+                    wg_socket.set_device(diff_to_uapi_device(interface.uapi_device(), &diff))
+                })?
+                .map_err(WgErrorInner::from)
+        })?;
+        interface.config = config;
+        Ok(true)
+    }
+
+    /// Resolve the hostnames of peer endpoints in a new configuration.
+    async fn resolve_endpoints(&self, mut config: Config) -> Result<Config, WgError> {
+        if !config.has_hostname_endpoints() {
+            return Ok(config);
+        }
+        let resolver = self.resolver.clone();
+        tokio::task::spawn_blocking(move || {
+            config.resolve_endpoints(&*resolver)?;
+            Ok::<_, WgError>(config)
+        })
+        .await
+        .map_err(WgErrorInner::from)?
+    }
+
+    /// Read the statistics of all live interfaces. Interfaces that can't be
+    /// queried are skipped.
+    pub(crate) async fn stats(&self) -> Vec<InterfaceStats> {
+        let queries = self.stats_queries().await;
+        let mut stats = Vec::with_capacity(queries.len());
+        for query in &queries {
+            match self.read_stats(query) {
+                Ok(interface_stats) => stats.push(interface_stats),
+                Err(err) => log::warn!(
+                    err:display,
+                    endpoint_id = query.endpoint_id.as_str();
+                    "Failed to read interface statistics"
+                ),
+            }
+        }
+        stats
+    }
+
+    /// Read the statistics of the interface of an endpoint, if it has one.
+    pub(crate) async fn endpoint_stats(
+        &self,
+        endpoint_id: EndpointId<'_>,
+    ) -> Result<Option<InterfaceStats>, WgError> {
+        let endpoint_id = endpoint_id.to_string();
+        let query = {
+            let interfaces = self.interfaces.lock().await;
+            let Some(interface) = interfaces.get(&endpoint_id) else {
+                return Ok(None);
+            };
+            interface.stats_query(&endpoint_id)
+        };
+        self.read_stats(&query).map(Some)
+    }
+
+    /// Copy what is needed to read the statistics of every live interface.
+    async fn stats_queries(&self) -> Vec<StatsQuery> {
+        let interfaces = self.interfaces.lock().await;
+        interfaces
+            .iter()
+            .map(|(endpoint_id, interface)| interface.stats_query(endpoint_id))
+            .collect()
+    }
+
+    /// The public key of the interface of an endpoint, as configured.
+    pub(crate) async fn endpoint_public_key(&self, endpoint_id: EndpointId<'_>) -> Option<Key> {
+        let interfaces = self.interfaces.lock().await;
+        let interface = interfaces.get(&endpoint_id.to_string())?;
+        Some(interface.config.private_key.as_ref()?.public_key())
+    }
+
+    fn read_stats(&self, query: &StatsQuery) -> Result<InterfaceStats, WgError> {
+        let sandbox = query.sandbox.as_ref();
+        let device = tokio::task::block_in_place(|| {
+            with_sandbox_socket(sandbox, &self.wg_socket, |wg_socket| {
+                wg_socket.get_device(sandbox_device_interface(&query.name, sandbox))
+            })?
+            .map_err(WgErrorInner::from)
+        })?;
+        // the private key in `device` is deliberately not copied
+        let peers = device
+            .peers
+            .into_iter()
+            .map(|peer| PeerStats {
+                public_key: peer.public_key.into(),
+                endpoint: peer.endpoint,
+                rx_bytes: peer.rx_bytes,
+                tx_bytes: peer.tx_bytes,
+                last_handshake: (!peer.last_handshake_time.is_zero())
+                    .then(|| SystemTime::UNIX_EPOCH + peer.last_handshake_time),
+                stale: query.stale_peers.contains(&peer.public_key.into()),
+            })
+            .collect();
+        Ok(InterfaceStats {
+            endpoint_id: query.endpoint_id.clone(),
+            if_name: query.name.clone(),
+            config_name: query.config_name.clone(),
+            public_key: device.public_key.map(Key::from),
+            listen_port: device.listen_port,
+            peers,
+        })
+    }
+
+    /// Periodically check that every peer with an endpoint had a recent
+    /// handshake. Peers are stale when the handshake is overdue; they are
+    /// reported in the logs, and optionally their endpoint is set again.
+    pub(crate) async fn watch_handshakes(&self, settings: WatchdogSettings) {
+        let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.check_handshakes(settings).await;
+        }
+    }
+
+    async fn check_handshakes(&self, settings: WatchdogSettings) {
+        // read without holding the lock, and only take it again to update
+        // the interfaces
+        let all_stats = self.stats().await;
+        let mut interfaces = self.interfaces.lock().await;
+        let now = SystemTime::now();
+        let mut recover = Vec::new();
+        for stats in all_stats {
+            let endpoint_id = &stats.endpoint_id;
+            // removed in the meantime
+            let Some(interface) = interfaces.get_mut(endpoint_id) else {
+                continue;
+            };
+            let mut stale = Vec::new();
+            for peer in stats.peers {
+                let Some(config) = interface
+                    .config
+                    .peers
+                    .iter()
+                    .find(|config| config.public_key == peer.public_key)
+                else {
+                    continue;
+                };
+                // peers without an endpoint can't be expected to connect
+                if config.endpoint.is_none() {
+                    continue;
+                }
+                let timeout = settings.timeout.unwrap_or_else(|| {
+                    config
+                        .persistent_keepalive
+                        .map_or(DEFAULT_HANDSHAKE_TIMEOUT, |keepalive| {
+                            Duration::from_secs(3 * u64::from(keepalive.get()))
+                        })
+                });
+                let since = peer.last_handshake.unwrap_or(interface.created);
+                if now.duration_since(since).unwrap_or_default() > timeout {
+                    stale.push(peer.public_key);
+                }
+            }
+
+            for public_key in &stale {
+                if !interface.stale_peers.contains(public_key) {
+                    log::warn!(
+                        endpoint_id = endpoint_id.as_str(),
+                        if_name = interface.name.as_str(),
+                        public_key:display;
+                        "No recent handshake with peer"
+                    );
+                }
+            }
+            for public_key in &interface.stale_peers {
+                if !stale.contains(public_key) {
+                    log::info!(
+                        endpoint_id = endpoint_id.as_str(),
+                        if_name = interface.name.as_str(),
+                        public_key:display;
+                        "Handshake with peer resumed"
+                    );
+                }
+            }
+            interface.stale_peers = stale;
+
+            if settings.recover && !interface.stale_peers.is_empty() {
+                recover.push((endpoint_id.clone(), interface.config.clone()));
+            }
+        }
+        drop(interfaces);
+        if recover.is_empty() {
+            return;
+        }
+
+        // resolve without holding the lock, since it can take a while
+        let resolver = self.resolver.clone();
+        let resolved = tokio::task::spawn_blocking(move || {
+            recover
+                .into_iter()
+                .map(|(endpoint_id, old)| {
+                    let mut new = old.clone();
+                    new.reresolve_endpoints(&*resolver);
+                    (endpoint_id, old, new)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await;
+        let Ok(resolved) = resolved else {
+            return;
+        };
+
+        let mut interfaces = self.interfaces.lock().await;
+        for (endpoint_id, old, new) in resolved {
+            let Some(interface) = interfaces.get_mut(&endpoint_id) else {
+                continue;
+            };
+            // reloaded in the meantime
+            if interface.config != old {
+                continue;
+            }
+            if let Err(err) = self.recover_peers(interface, new) {
+                log::error!(
+                    err:display,
+                    endpoint_id = endpoint_id.as_str();
+                    "Failed to set endpoints of stale peers"
+                );
+            }
+        }
+    }
+
+    /// Set the endpoints of stale peers, as resolved again in `config`, on
+    /// the interface, even if they did not change.
+    fn recover_peers(&self, interface: &mut LiveInterface, config: Config) -> Result<(), WgError> {
+        let mut device = interface.uapi_device();
+        device.peers.extend(
+            config
+                .peers
+                .iter()
+                .filter(|peer| interface.stale_peers.contains(&peer.public_key))
+                .filter_map(|peer| {
+                    let endpoint = peer.endpoint.as_ref().and_then(PeerEndpoint::addr)?;
+                    Some(
+                        wireguard_uapi::set::Peer::from_public_key(peer.public_key.bytes())
+                            .endpoint(endpoint),
+                    )
+                }),
+        );
+        tokio::task::block_in_place(|| {
+            interface
+                .with_socket(&self.wg_socket, |wg_socket| wg_socket.set_device(device))?
+                .map_err(WgErrorInner::from)
+        })?;
+        interface.config = config;
+        Ok(())
+    }
+
+    /// Pick an MTU the same way wg-quick does: take the largest MTU of the
+    /// routes towards the peer endpoints, or the MTU of the default route if
+    /// there are none, and subtract the WireGuard overhead.
+    async fn auto_mtu(&self, config: &Config) -> u32 {
+        let mut mtu = None;
+        let endpoints = config
+            .peers
+            .iter()
+            .filter_map(|peer| peer.endpoint.as_ref());
+        for endpoint in endpoints.filter_map(PeerEndpoint::addr) {
+            if let Some(route_mtu) = route_mtu(self.rt.clone(), endpoint.ip()).await {
+                mtu = mtu.max(Some(route_mtu));
+            }
+        }
+        let mtu = match mtu {
+            Some(mtu) => mtu,
+            None => default_route_mtu(self.rt.clone())
+                .await
+                .unwrap_or(DEFAULT_LINK_MTU),
+        };
+        tunnel_mtu(mtu)
+    }
+
+    fn interface_name(&self, endpoint_id: EndpointId<'_>) -> String {
         let suffix = &endpoint_id.to_string()[0..8];
-        format!("wgdkr{suffix}")
+        format!("{}{suffix}", self.interface_prefix)
+    }
+}
+
+/// MTU assumed for the underlying link when there is no route to tell.
+const DEFAULT_LINK_MTU: u32 = 1500;
+
+/// Worst case WireGuard encapsulation overhead (IPv6 header + UDP + WireGuard).
+const WIREGUARD_OVERHEAD: u32 = 80;
+
+/// The MTU of a tunnel over a link with `link_mtu`, within the range of MTUs
+/// that a configuration can set.
+fn tunnel_mtu(link_mtu: u32) -> u32 {
+    link_mtu
+        .saturating_sub(WIREGUARD_OVERHEAD)
+        .clamp(*MTU_RANGE.start(), *MTU_RANGE.end())
+}
+
+/// The routing table that `ip route` shows by default.
+const RT_TABLE_MAIN: u8 = 254;
+
+/// Find the MTU of the route the host would use to reach `destination`, like
+/// `ip route get` does.
+async fn route_mtu(handle: rtnetlink::Handle, destination: IpAddr) -> Option<u32> {
+    let mut message = RouteMessage::default();
+    match destination {
+        IpAddr::V4(addr) => {
+            message.header.address_family = AddressFamily::Inet;
+            message.header.destination_prefix_length = 32;
+            message
+                .attributes
+                .push(RouteAttribute::Destination(RouteAddress::Inet(addr)));
+        }
+        IpAddr::V6(addr) => {
+            message.header.address_family = AddressFamily::Inet6;
+            message.header.destination_prefix_length = 128;
+            message
+                .attributes
+                .push(RouteAttribute::Destination(RouteAddress::Inet6(addr)));
+        }
+    }
+    let mut request = NetlinkMessage::from(RouteNetlinkMessage::GetRoute(message));
+    // Not a dump: we want the kernel to resolve the route for us.
+    request.header.flags = NLM_F_REQUEST;
+
+    let route = find_route(handle.clone(), request, |_| true).await;
+    if route.is_none() {
+        log::debug!(destination:display; "No route to endpoint");
+    }
+    route_message_mtu(handle, route?).await
+}
+
+/// Find the MTU of the default route, like wg-quick does when no endpoint
+/// has a route. IPv4 is tried first.
+async fn default_route_mtu(handle: rtnetlink::Handle) -> Option<u32> {
+    for family in [AddressFamily::Inet, AddressFamily::Inet6] {
+        let mut message = RouteMessage::default();
+        message.header.address_family = family;
+        let mut request = NetlinkMessage::from(RouteNetlinkMessage::GetRoute(message));
+        request.header.flags = NLM_F_REQUEST | NLM_F_DUMP;
+
+        let route = find_route(handle.clone(), request, |route| {
+            route.header.destination_prefix_length == 0
+                && route.header.table == RT_TABLE_MAIN
+                && route.header.kind == RouteType::Unicast
+        })
+        .await;
+        if let Some(route) = route {
+            if let Some(mtu) = route_message_mtu(handle.clone(), route).await {
+                return Some(mtu);
+            }
+        }
+    }
+    log::debug!("No default route to take the MTU from");
+    None
+}
+
+/// Send a route request, and return the first route of the response that
+/// matches `filter`.
+async fn find_route(
+    mut handle: rtnetlink::Handle,
+    request: NetlinkMessage<RouteNetlinkMessage>,
+    filter: impl Fn(&RouteMessage) -> bool,
+) -> Option<RouteMessage> {
+    let mut response = match handle.request(request) {
+        Ok(response) => response,
+        Err(err) => {
+            log::debug!(err:display; "Route lookup failed");
+            return None;
+        }
+    };
+    while let Some(message) = response.next().await {
+        match message.payload {
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewRoute(route))
+                if filter(&route) =>
+            {
+                return Some(route);
+            }
+            NetlinkPayload::Error(err) if err.code.is_some() => {
+                log::debug!(err:?; "Route lookup failed");
+                return None;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The MTU of a route: its MTU metric when present, otherwise the MTU of
+/// the output device.
+async fn route_message_mtu(handle: rtnetlink::Handle, route: RouteMessage) -> Option<u32> {
+    let mut oif = None;
+    for attr in route.attributes {
+        match attr {
+            RouteAttribute::Metrics(metrics) => {
+                for metric in metrics {
+                    if let RouteMetric::Mtu(mtu) = metric {
+                        if mtu > 0 {
+                            return Some(mtu);
+                        }
+                    }
+                }
+            }
+            RouteAttribute::Oif(index) => oif = Some(index),
+            _ => {}
+        }
+    }
+
+    let mut links = handle.link().get().match_index(oif?).execute();
+    match links.try_next().await {
+        Ok(Some(link)) => link.attributes.iter().find_map(|attr| {
+            if let LinkAttribute::Mtu(mtu) = attr {
+                Some(*mtu)
+            } else {
+                None
+            }
+        }),
+        Ok(None) => None,
+        Err(err) => {
+            log::debug!(err:display; "Failed to get output link");
+            None
+        }
+    }
+}
+
+async fn link_index(handle: rtnetlink::Handle, name: String) -> Result<u32, rtnetlink::Error> {
+    let mut links = handle.link().get().match_name(name).execute();
+    match links.try_next().await? {
+        Some(link) => Ok(link.header.index),
+        None => Err(rtnetlink::Error::RequestFailed),
     }
 }
 
@@ -115,16 +1027,10 @@ async fn delete_link_if_found(
         .attributes
         .push(LinkAttribute::IfName(name));
 
-    const ENODEV: i32 = rustix::io::Errno::NODEV.raw_os_error();
-    if let Err(err) = request.execute().await {
-        if let rtnetlink::Error::NetlinkError(err) = &err {
-            if err.raw_code() == ENODEV {
-                return Ok(false);
-            }
-        }
-        Err(err)
-    } else {
-        Ok(true)
+    match request.execute().await {
+        Ok(()) => Ok(true),
+        Err(err) if is_errno(&err, rustix::io::Errno::NODEV) => Ok(false),
+        Err(err) => Err(err),
     }
 
     // // old implementation
@@ -137,12 +1043,24 @@ async fn delete_link_if_found(
     // }
 }
 
+/// Whether a netlink request failed with `errno`. The kernel reports netlink
+/// errors as negative error numbers.
+fn is_errno(err: &rtnetlink::Error, errno: rustix::io::Errno) -> bool {
+    match err {
+        rtnetlink::Error::NetlinkError(message) => message.raw_code().abs() == errno.raw_os_error(),
+        _ => false,
+    }
+}
+
 fn config_to_uapi_device<'a>(
     if_name: &'a str,
     config: &'a Config,
 ) -> wireguard_uapi::set::Device<'a> {
-    let mut device =
-        wireguard_uapi::set::Device::from_ifname(if_name).private_key(config.private_key.bytes());
+    let mut device = wireguard_uapi::set::Device::from_ifname(if_name);
+
+    if let Some(private_key) = &config.private_key {
+        device = device.private_key(private_key.bytes());
+    }
 
     if let Some(port) = config.listen_port {
         device = device.listen_port(port);
@@ -152,33 +1070,115 @@ fn config_to_uapi_device<'a>(
         device = device.fwmark(fw_mark);
     }
 
-    device.peers.extend(config.peers.iter().map(|peer_config| {
-        let mut peer = wireguard_uapi::set::Peer::from_public_key(peer_config.public_key.bytes());
-        if let Some(psk) = &peer_config.preshared_key {
-            peer = peer.preshared_key(psk.bytes());
-        }
-        if let Some(endpoint) = &peer_config.endpoint {
-            peer = peer.endpoint(endpoint);
-        }
-        peer.allowed_ips
-            .extend(
-                peer_config
-                    .allowed_ips
-                    .iter()
-                    .map(|ip| wireguard_uapi::set::AllowedIp {
-                        ipaddr: ip.ip(),
-                        cidr_mask: Some(ip.cidr()),
-                    }),
-            );
-        if let Some(pk) = peer_config.persistent_keepalive {
-            peer = peer.persistent_keepalive_interval(pk.get());
-        }
-        peer
-    }));
+    device
+        .peers
+        .extend(config.peers.iter().map(peer_to_uapi_peer));
+
+    device
+}
+
+/// Build a device update that only includes what changed.
+fn diff_to_uapi_device<'a>(
+    mut device: wireguard_uapi::set::Device<'a>,
+    diff: &'a ConfigDiff<'a>,
+) -> wireguard_uapi::set::Device<'a> {
+    if let Some(private_key) = diff.private_key {
+        device = device.private_key(private_key.bytes());
+    }
+
+    if let Some(port) = diff.listen_port {
+        // zero picks a random port
+        device = device.listen_port(port.unwrap_or(0));
+    }
+
+    if let Some(fw_mark) = diff.fw_mark {
+        device = device.fwmark(fw_mark.unwrap_or(0));
+    }
+
+    device
+        .peers
+        .extend(diff.removed_peers.iter().map(remove_uapi_peer));
+    device
+        .peers
+        .extend(diff.changed_peers.iter().copied().map(replace_uapi_peer));
 
     device
 }
 
+/// Build a device update that sets everything in `config`, whatever the
+/// interface had before, and removes `removed_peers`.
+fn replace_uapi_device<'a>(
+    mut device: wireguard_uapi::set::Device<'a>,
+    config: &'a Config,
+    removed_peers: &'a [Key],
+) -> wireguard_uapi::set::Device<'a> {
+    if let Some(private_key) = &config.private_key {
+        device = device.private_key(private_key.bytes());
+    }
+
+    // zero would pick another random port
+    if let Some(port) = config.listen_port {
+        device = device.listen_port(port);
+    }
+
+    device = device.fwmark(config.fw_mark.unwrap_or(0));
+
+    device
+        .peers
+        .extend(removed_peers.iter().map(remove_uapi_peer));
+    device
+        .peers
+        .extend(config.peers.iter().map(replace_uapi_peer));
+
+    device
+}
+
+fn remove_uapi_peer(key: &Key) -> wireguard_uapi::set::Peer<'_> {
+    use wireguard_uapi::set::WgPeerF;
+
+    wireguard_uapi::set::Peer::from_public_key(key.bytes()).flags(vec![WgPeerF::RemoveMe])
+}
+
+/// A peer update that replaces every setting of the peer, including the
+/// ones that are not in the configuration.
+fn replace_uapi_peer(peer_config: &Peer) -> wireguard_uapi::set::Peer<'_> {
+    use wireguard_uapi::set::WgPeerF;
+
+    let mut peer = peer_to_uapi_peer(peer_config).flags(vec![WgPeerF::ReplaceAllowedIps]);
+    if peer_config.preshared_key.is_none() {
+        // an all-zero key removes the preshared key
+        peer = peer.preshared_key(&[0; 32]);
+    }
+    if peer_config.persistent_keepalive.is_none() {
+        peer = peer.persistent_keepalive_interval(0);
+    }
+    peer
+}
+
+fn peer_to_uapi_peer(peer_config: &Peer) -> wireguard_uapi::set::Peer<'_> {
+    let mut peer = wireguard_uapi::set::Peer::from_public_key(peer_config.public_key.bytes());
+    if let Some(psk) = &peer_config.preshared_key {
+        peer = peer.preshared_key(psk.bytes());
+    }
+    if let Some(endpoint) = peer_config.endpoint.as_ref().and_then(PeerEndpoint::addr) {
+        peer = peer.endpoint(endpoint);
+    }
+    peer.allowed_ips
+        .extend(
+            peer_config
+                .allowed_ips
+                .iter()
+                .map(|ip| wireguard_uapi::set::AllowedIp {
+                    ipaddr: ip.ip(),
+                    cidr_mask: Some(ip.cidr()),
+                }),
+        );
+    if let Some(pk) = peer_config.persistent_keepalive {
+        peer = peer.persistent_keepalive_interval(pk.get());
+    }
+    peer
+}
+
 const fn nl_mgrp(group: u32) -> u32 {
     if group > 31 {
         panic!("use netlink_sys::Socket::add_membership() for this group");
@@ -196,10 +1196,12 @@ struct LinkWatcher {
     rt: rtnetlink::Handle,
     watcher_task: JoinHandle<()>,
     marked_for_deletion: Arc<AsyncMutex<Vec<String>>>,
+    /// The list of marked interfaces is persisted here, to survive restarts.
+    db: Arc<Db>,
 }
 
 impl LinkWatcher {
-    pub(crate) fn new() -> Result<Self, WgError> {
+    pub(crate) fn new(db: Arc<Db>) -> Result<Self, WgError> {
         let (mut rt_connection, rt, mut messages) = new_connection().map_err(WgErrorInner::from)?;
 
         // use netlink_proto::sys::{AsyncSocket, SocketAddr};
@@ -216,9 +1218,16 @@ impl LinkWatcher {
         let messages_task = tokio::spawn({
             let marked_for_deletion = marked_for_deletion.clone();
             let rt = rt.clone();
+            let db = db.clone();
             async move {
                 while let Some((message, _)) = messages.next().await {
-                    Self::process_message(rt.clone(), marked_for_deletion.clone(), message).await;
+                    Self::process_message(
+                        rt.clone(),
+                        db.clone(),
+                        marked_for_deletion.clone(),
+                        message,
+                    )
+                    .await;
                 }
             }
         });
@@ -230,6 +1239,7 @@ impl LinkWatcher {
             rt,
             watcher_task: messages_task,
             marked_for_deletion,
+            db,
         })
     }
 
@@ -237,11 +1247,25 @@ impl LinkWatcher {
         let mut list = self.marked_for_deletion.lock().await;
         if !list.contains(&name) {
             list.push(name);
+            save_pending_deletions(&self.db, &list);
+        }
+    }
+
+    async fn unmark(&self, name: &str) {
+        let mut list = self.marked_for_deletion.lock().await;
+        if let Some(pos) = list.iter().position(|n| n == name) {
+            list.remove(pos);
+            save_pending_deletions(&self.db, &list);
         }
     }
 
+    async fn persist(&self) {
+        save_pending_deletions(&self.db, &self.marked_for_deletion.lock().await);
+    }
+
     async fn process_message(
         rt: rtnetlink::Handle,
+        db: Arc<Db>,
         marked_for_deletion: Arc<AsyncMutex<Vec<String>>>,
         message: NetlinkMessage<RouteNetlinkMessage>,
     ) {
@@ -263,6 +1287,7 @@ impl LinkWatcher {
                         })
                     {
                         list.remove(pos);
+                        save_pending_deletions(&db, &list);
                     }
                 }
             }
@@ -273,6 +1298,7 @@ impl LinkWatcher {
                 let mut list = marked_for_deletion.lock().await;
                 if let Some(pos) = list.iter().position(|n| n == name) {
                     list.remove(pos);
+                    save_pending_deletions(&db, &list);
                 }
             }
             _ => {}
@@ -288,6 +1314,25 @@ impl Drop for LinkWatcher {
     }
 }
 
+fn save_pending_deletions(db: &Db, list: &[String]) {
+    if let Err(err) = tokio::task::block_in_place(|| db.set_pending_deletions(list)) {
+        log::error!(err:display; "Failed to save interfaces pending deletion");
+    }
+}
+
+/// Whether `name` has the form of the names given by `Wg::interface_name`.
+fn is_interface_name(prefix: &str, name: &str) -> bool {
+    name.strip_prefix(prefix)
+        .is_some_and(|suffix| suffix.len() == 8 && suffix.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+fn is_wireguard_link(link: &LinkMessage) -> bool {
+    link.attributes.iter().any(|attr| {
+        matches!(attr, LinkAttribute::LinkInfo(infos)
+            if infos.contains(&LinkInfo::Kind(InfoKind::Wireguard)))
+    })
+}
+
 fn get_name_from_link(link: &LinkMessage) -> Option<&String> {
     link.attributes.iter().find_map(|attr| {
         if let LinkAttribute::IfName(name) = attr {
@@ -297,3 +1342,58 @@ fn get_name_from_link(link: &LinkMessage) -> Option<&String> {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::NetworkId;
+
+    #[test]
+    fn test_tunnel_mtu() {
+        assert_eq!(tunnel_mtu(DEFAULT_LINK_MTU), 1420);
+        assert_eq!(tunnel_mtu(9000), 8920);
+        // such as a route with `mtu 576`
+        assert_eq!(tunnel_mtu(576), 1280);
+        assert_eq!(tunnel_mtu(0), 1280);
+        assert_eq!(tunnel_mtu(u32::MAX), 65535);
+    }
+
+    #[test]
+    fn test_interface_name() {
+        assert!(is_interface_name("wgdkr-", "wgdkr-0123abcd"));
+        assert!(is_interface_name("wg", "wg0123abcd"));
+        assert!(!is_interface_name("wg", "wg0"));
+        assert!(!is_interface_name("wg", "wg-home"));
+        assert!(!is_interface_name("wg", "wg0123abcde"));
+        assert!(!is_interface_name("wg", "wg0123abcg"));
+        assert!(!is_interface_name("wgdkr-", "eth0123abcd"));
+    }
+
+    #[test]
+    fn test_restored_interface() {
+        let text = "[Interface]\nPrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\n";
+        let config = crate::wg::parse_config_diagnostics(text).0.unwrap();
+        let restored =
+            |endpoint: &Endpoint| LiveInterface::restored(endpoint, Some("mynet"), config.clone());
+
+        let mut endpoint = Endpoint::new(NetworkId::new("n1"), Vec::new());
+        assert!(restored(&endpoint).is_none());
+
+        // joined, but the link was not moved to the sandbox yet
+        let sandbox_key = PathBuf::from("/var/run/docker/netns/0123456789ab");
+        endpoint.join("wgdkr-e1".to_owned(), sandbox_key.clone(), config.digest());
+        let interface = restored(&endpoint).unwrap();
+        assert_eq!(interface.name, "wgdkr-e1");
+        assert_eq!(interface.config_name.as_deref(), Some("mynet"));
+        assert_eq!(interface.config, config);
+        assert_eq!(interface.sandbox, None);
+        assert!(interface.stale_peers.is_empty());
+
+        endpoint.set_sandbox_index(7);
+        let interface = restored(&endpoint).unwrap();
+        assert_eq!(interface.sandbox, Some((sandbox_key, 7)));
+
+        endpoint.leave();
+        assert!(restored(&endpoint).is_none());
+    }
+}